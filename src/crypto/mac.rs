@@ -2,7 +2,9 @@
 
 //https://en.wikipedia.org/wiki/ISO/IEC_9797-1#Complete_specification_of_the_MAC_calculation
 
-use crate::crypto::{tdes_encrypt_cbc, des_encrypt_cbc};
+use std::fmt;
+
+use crate::crypto::{CryptoError, tdes_encrypt_cbc, des_encrypt_cbc};
 
 /// This enum defines various supported algorithms
 pub enum MacAlgo {
@@ -20,8 +22,39 @@ pub enum PaddingType {
     Type2,
 }
 
-pub struct MacError {
-    pub msg: String
+/// MacError represents a failure to generate or verify a MAC
+#[derive(Debug)]
+pub enum MacError {
+    /// The computed MAC doesn't match the expected MAC
+    Mismatch { computed: Vec<u8>, expected: Vec<u8> },
+    /// The key could not be used
+    Crypto(CryptoError),
+}
+
+impl fmt::Display for MacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacError::Mismatch { computed, expected } => {
+                write!(f, "computed mac: {} doesn't match expected_mac: {}", hex::encode(computed), hex::encode(expected))
+            }
+            MacError::Crypto(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for MacError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MacError::Crypto(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<CryptoError> for MacError {
+    fn from(e: CryptoError) -> Self {
+        MacError::Crypto(e)
+    }
 }
 
 
@@ -30,11 +63,15 @@ pub fn verify_mac(algo: &MacAlgo, padding_type: &PaddingType, data: &[u8], key:
     if mac.eq(expected_mac) {
         Ok(())
     } else {
-        Err(MacError { msg: format!("computed mac: {} doesn't match expected_mac: {}", hex::encode(mac), hex::encode(expected_mac)) })
+        Err(MacError::Mismatch { computed: mac, expected: expected_mac.clone() })
     }
 }
 
 pub fn generate_mac(algo: &MacAlgo, padding_type: &PaddingType, data: &Vec<u8>, key: &Vec<u8>) -> Result<Vec<u8>, MacError> {
+    if key.len() != 16 {
+        return Err(MacError::Crypto(CryptoError::InvalidKey(format!("expected 16 bytes, found {}", key.len()))));
+    }
+
     let new_data = apply_padding(padding_type, data);
     let mut iv = Vec::<u8>::new();
     iv.extend_from_slice(hex::decode("0000000000000000").unwrap().as_slice());
@@ -139,7 +176,7 @@ mod tests {
                 assert_eq!("7d34c3071da931b9", hex::encode(m));
             }
             Err(e) => {
-                assert!(false, e.msg)
+                assert!(false, "{}", e)
             }
         }
     }
@@ -154,7 +191,7 @@ mod tests {
                 assert_eq!("0fe28f4b5537ee79", hex::encode(m));
             }
             Err(e) => {
-                assert!(false, e.msg)
+                assert!(false, "{}", e)
             }
         }
     }
@@ -170,7 +207,7 @@ mod tests {
                 assert_eq!("8fb12963d5661a22", hex::encode(m));
             }
             Err(e) => {
-                assert!(false, e.msg)
+                assert!(false, "{}", e)
             }
         }
     }
@@ -185,7 +222,7 @@ mod tests {
                 assert_eq!("8568cd2b7698605f", hex::encode(m));
             }
             Err(e) => {
-                assert!(false, e.msg)
+                assert!(false, "{}", e)
             }
         }
     }
@@ -201,7 +238,7 @@ mod tests {
                 assert_eq!("7d34c3071da931b9", hex::encode(m));
             }
             Err(e) => {
-                assert!(false, e.msg)
+                assert!(false, "{}", e)
             }
         }
     }
@@ -216,7 +253,7 @@ mod tests {
                 assert_eq!(hex::encode(m), "149f99288681d292");
             }
             Err(e) => {
-                assert!(false, e.msg)
+                assert!(false, "{}", e)
             }
         }
     }
@@ -231,7 +268,7 @@ mod tests {
                 assert_eq!(hex::encode(m), "4689dd5a87015394");
             }
             Err(e) => {
-                assert!(false, e.msg)
+                assert!(false, "{}", e)
             }
         }
     }
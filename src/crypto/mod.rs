@@ -7,6 +7,8 @@ extern crate block_modes;
 extern crate hex_literal;
 
 
+use std::fmt;
+
 use generic_array::{GenericArray};
 use des::block_cipher::NewBlockCipher;
 use des::block_cipher::BlockCipher;
@@ -15,10 +17,33 @@ use des::block_cipher::BlockCipher;
 use self::block_modes::{BlockMode};
 
 
-/// CryptoError is a generic error in processing within this crate
-#[allow(unused)]
-pub(crate) struct CryptoError {
-    pub(crate) msg: String
+/// CryptoError represents a failure of a cryptographic operation within this crate
+#[derive(Debug)]
+pub enum CryptoError {
+    /// The key is not valid hex or is not of the length required by the cipher
+    InvalidKey(String),
+    /// The cipher failed to process the data
+    Cipher(String),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::InvalidKey(msg) => write!(f, "invalid key - {}", msg),
+            CryptoError::Cipher(msg) => write!(f, "cipher error - {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// Decodes a hex encoded double length (16 byte) DES key
+pub(crate) fn decode_key(key: &str) -> Result<Vec<u8>, CryptoError> {
+    match hex::decode(key) {
+        Ok(k) if k.len() == 16 => Ok(k),
+        Ok(k) => Err(CryptoError::InvalidKey(format!("expected 16 bytes, found {}", k.len()))),
+        Err(e) => Err(CryptoError::InvalidKey(e.to_string())),
+    }
 }
 
 pub(crate) fn tdes_ede2_encrypt(data: &Vec<u8>, key: &Vec<u8>) -> Vec<u8> {
@@ -63,7 +88,7 @@ pub(crate) fn des_decrypt_cbc(data: &Vec<u8>, key: &Vec<u8>, iv: &Vec<u8>) -> Re
             Ok(d)
         }
         Err(e) => {
-            Err(CryptoError { msg: e.to_string() })
+            Err(CryptoError::Cipher(e.to_string()))
         }
     }
 }
\ No newline at end of file
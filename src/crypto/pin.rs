@@ -2,12 +2,14 @@
 
 /// More info here - https://www.eftlab.com/knowledge-base/261-complete-list-of-pin-blocks-in-payments/
 
+use std::fmt;
+
 use rand;
 use super::rand::Rng;
-use crate::crypto::{tdes_ede2_decrypt, tdes_ede2_encrypt};
+use crate::crypto::{CryptoError, decode_key, tdes_ede2_decrypt, tdes_ede2_encrypt};
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PinFormat {
     //ANSI X9.8, ECI-4
     ISO0,
@@ -17,11 +19,65 @@ pub enum PinFormat {
     ISO4,
 }
 
-pub struct PinError {
-    pub msg: String
+/// PinError represents a failure to generate or verify a PIN block
+#[derive(Debug)]
+pub enum PinError {
+    /// The PIN block format is not supported
+    UnsupportedFormat(PinFormat),
+    /// The clear PIN is not valid (must be 4 to 12 digits)
+    InvalidPin(String),
+    /// The PAN is too short to be used in the PIN block
+    InvalidPan(String),
+    /// The PIN block is not 8 bytes or doesn't decrypt to a valid PIN
+    InvalidPinBlock(String),
+    /// The key could not be used
+    Crypto(CryptoError),
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PinError::UnsupportedFormat(fmt) => write!(f, "{:?} is not supported yet.", fmt),
+            PinError::InvalidPin(msg) => write!(f, "invalid pin - {}", msg),
+            PinError::InvalidPan(pan) => write!(f, "invalid pan - {}", pan),
+            PinError::InvalidPinBlock(msg) => write!(f, "invalid pin block - {}", msg),
+            PinError::Crypto(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PinError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PinError::Crypto(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<CryptoError> for PinError {
+    fn from(e: CryptoError) -> Self {
+        PinError::Crypto(e)
+    }
+}
+
+/// Returns the rightmost 12 digits of the pan (excluding the check digit)
+fn pan_12(pan: &str) -> Result<&str, PinError> {
+    match pan.get(pan.len().saturating_sub(13)..pan.len().saturating_sub(1)) {
+        Some(p) if pan.len() >= 13 => Ok(p),
+        _ => Err(PinError::InvalidPan(pan.to_string()))
+    }
 }
 
 pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &str) -> Result<Vec<u8>, PinError> {
+    if !c_pin.chars().all(|c| c.is_ascii_digit()) {
+        return Err(PinError::InvalidPin("should only contain digits".to_string()));
+    }
+    if c_pin.len() < 4 || c_pin.len() > 12 {
+        return Err(PinError::InvalidPin(format!("{} digit(s), should be 4 to 12", c_pin.len())));
+    }
+    let key = decode_key(key)?;
+
     match fmt {
         PinFormat::ISO0 => {
             let mut b1 = format!("0{:X}{}", c_pin.len(), c_pin);
@@ -30,10 +86,10 @@ pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &str) ->
 
             //rightmost 12 not including check digit
             let mut b2 = String::from("0000");
-            b2.push_str(&pan[pan.len() - 13..pan.len() - 1]);
+            b2.push_str(pan_12(pan)?);
 
             let res = xor_hexstr(b1.as_str(), b2.as_str());
-            let res = tdes_ede2_encrypt(&res, &hex::decode(key).unwrap().to_vec());
+            let res = tdes_ede2_encrypt(&res, &key);
 
             Ok(res.to_vec())
         }
@@ -42,11 +98,11 @@ pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &str) ->
             pad_8(&mut b1);
             match hex::decode(b1) {
                 Ok(res) => {
-                    let res = tdes_ede2_encrypt(&res, &hex::decode(key).unwrap().to_vec());
+                    let res = tdes_ede2_encrypt(&res, &key);
                     Ok(res)
                 }
                 Err(e) => {
-                    Err(PinError { msg: e.to_string() })
+                    Err(PinError::InvalidPin(e.to_string()))
                 }
             }
         }
@@ -59,11 +115,11 @@ pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &str) ->
             println!("= {}", b1);
             match hex::decode(b1) {
                 Ok(res) => {
-                    let res = tdes_ede2_encrypt(&res, &hex::decode(key).unwrap().to_vec());
+                    let res = tdes_ede2_encrypt(&res, &key);
                     Ok(res)
                 }
                 Err(e) => {
-                    Err(PinError { msg: e.to_string() })
+                    Err(PinError::InvalidPin(e.to_string()))
                 }
             }
         }
@@ -75,16 +131,16 @@ pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &str) ->
 
             //rightmost 12 not including check digit
             let mut b2 = String::from("0000");
-            b2.push_str(&pan[pan.len() - 13..pan.len() - 1]);
+            b2.push_str(pan_12(pan)?);
 
             let res = xor_hexstr(b1.as_str(), b2.as_str());
-            let res = tdes_ede2_encrypt(&res, &hex::decode(key).unwrap().to_vec());
+            let res = tdes_ede2_encrypt(&res, &key);
 
             Ok(res.to_vec())
         }
 
         _ => {
-            Err(PinError { msg: format!("{:?} is not supported yet.", fmt) })
+            Err(PinError::UnsupportedFormat(*fmt))
         }
     }
 }
@@ -93,16 +149,20 @@ pub fn generate_pin_block(fmt: &PinFormat, c_pin: &str, pan: &str, key: &str) ->
 /// was a successful match
 pub fn verify_pin(fmt: &PinFormat, expected_pin: &str, pin_block: &Vec<u8>, pan: &str, key: &str) -> Result<bool, PinError> {
     debug!("verifying pin - expected_pin: {},  block: {}, pan:{}, key:{}", expected_pin, hex::encode(pin_block), pan, key);
+    if pin_block.len() != 8 {
+        return Err(PinError::InvalidPinBlock(format!("expected 8 bytes, found {}", pin_block.len())));
+    }
+    let key = decode_key(key)?;
+
     match fmt {
         PinFormat::ISO0 => {
             let mut b2 = String::from("0000");
-            b2.push_str(&pan[pan.len() - 13..pan.len() - 1]);
+            b2.push_str(pan_12(pan)?);
 
-            let res = tdes_ede2_decrypt(&pin_block, &hex::decode(key).unwrap().to_vec());
+            let res = tdes_ede2_decrypt(pin_block, &key);
             let res = xor_hexstr(hex::encode(res.as_slice()).as_str(), b2.as_str());
-            let pin_len = res.get(0).unwrap();
             let b1 = hex::encode(&res);
-            let actual_pin = b1.get(2 as usize..(2 + pin_len) as usize).unwrap().clone();
+            let actual_pin = actual_pin(&b1, res[0], 0x00)?;
             if expected_pin == actual_pin {
                 Ok(true)
             } else {
@@ -111,11 +171,10 @@ pub fn verify_pin(fmt: &PinFormat, expected_pin: &str, pin_block: &Vec<u8>, pan:
         }
 
         PinFormat::ISO1 => {
-            let res = tdes_ede2_decrypt(&pin_block, &hex::decode(key).unwrap().to_vec());
+            let res = tdes_ede2_decrypt(pin_block, &key);
 
-            let pin_len = res.get(0).unwrap();
             let b1 = hex::encode(&res);
-            let actual_pin = b1.get(2 as usize..(2 + (pin_len - 16)) as usize).unwrap().clone();
+            let actual_pin = actual_pin(&b1, res[0], 0x10)?;
             if expected_pin == actual_pin {
                 Ok(true)
             } else {
@@ -123,11 +182,10 @@ pub fn verify_pin(fmt: &PinFormat, expected_pin: &str, pin_block: &Vec<u8>, pan:
             }
         }
         PinFormat::ISO2 => {
-            let res = tdes_ede2_decrypt(&pin_block, &hex::decode(key).unwrap().to_vec());
+            let res = tdes_ede2_decrypt(pin_block, &key);
 
-            let pin_len = res.get(0).unwrap();
             let b1 = hex::encode(&res);
-            let actual_pin = b1.get(2 as usize..(2 + (pin_len - 32)) as usize).unwrap().clone();
+            let actual_pin = actual_pin(&b1, res[0], 0x20)?;
             if expected_pin == actual_pin {
                 Ok(true)
             } else {
@@ -136,13 +194,12 @@ pub fn verify_pin(fmt: &PinFormat, expected_pin: &str, pin_block: &Vec<u8>, pan:
         }
         PinFormat::ISO3 => {
             let mut b2 = String::from("0000");
-            b2.push_str(&pan[pan.len() - 13..pan.len() - 1]);
+            b2.push_str(pan_12(pan)?);
 
-            let res = tdes_ede2_decrypt(&pin_block, &hex::decode(key).unwrap().to_vec());
+            let res = tdes_ede2_decrypt(pin_block, &key);
             let res = xor_hexstr(hex::encode(res.as_slice()).as_str(), b2.as_str());
-            let pin_len = res.get(0).unwrap();
             let b1 = hex::encode(&res);
-            let actual_pin = b1.get(2 as usize..(2 + (pin_len - 48)) as usize).unwrap().clone();
+            let actual_pin = actual_pin(&b1, res[0], 0x30)?;
             if expected_pin == actual_pin {
                 Ok(true)
             } else {
@@ -150,12 +207,20 @@ pub fn verify_pin(fmt: &PinFormat, expected_pin: &str, pin_block: &Vec<u8>, pan:
             }
         }
         _ => {
-            Err(PinError { msg: format!("{:?} is not supported yet.", fmt) })
+            Err(PinError::UnsupportedFormat(*fmt))
         }
     }
 }
 
 
+/// Extracts the clear pin from the (hex encoded) decrypted block given the control field of the format
+fn actual_pin(b1: &str, control: u8, format_id: u8) -> Result<&str, PinError> {
+    let pin_len = control.wrapping_sub(format_id) as usize;
+    match b1.get(2..2 + pin_len) {
+        Some(pin) if (4..=12).contains(&pin_len) => Ok(pin),
+        _ => Err(PinError::InvalidPinBlock(format!("bad control field - {:02x}", control)))
+    }
+}
 
 /// XOR the contents of 2 hex string (of equal length) and return the result
 /// as a Vec<u8>
@@ -189,8 +254,9 @@ fn pad_8_a2f(data: &mut String) {
 
 #[cfg(test)]
 mod tests {
-    use crate::crypto::pin::{generate_pin_block, verify_pin};
-    use crate::crypto::pin::PinFormat::{ISO0, ISO1, ISO2, ISO3};
+    use crate::crypto::CryptoError;
+    use crate::crypto::pin::{generate_pin_block, verify_pin, PinError};
+    use crate::crypto::pin::PinFormat::{ISO0, ISO1, ISO2, ISO3, ISO4};
 
     #[test]
     fn test_iso0() {
@@ -203,12 +269,12 @@ mod tests {
                         assert_eq!(res, true)
                     }
                     Err(e) => {
-                        assert!(false, e.msg.to_string());
+                        assert!(false, "{}", e);
                     }
                 }
             }
             Err(e) => {
-                assert!(false, e.msg.to_string());
+                assert!(false, "{}", e);
             }
         }
 
@@ -221,12 +287,12 @@ mod tests {
                         assert_eq!(res, true)
                     }
                     Err(e) => {
-                        assert!(false, e.msg.to_string());
+                        assert!(false, "{}", e);
                     }
                 }
             }
             Err(e) => {
-                assert!(false, e.msg.to_string());
+                assert!(false, "{}", e);
             }
         }
     }
@@ -240,12 +306,12 @@ mod tests {
                         assert_eq!(res, true)
                     }
                     Err(e) => {
-                        assert!(false, e.msg.to_string());
+                        assert!(false, "{}", e);
                     }
                 }
             }
             Err(e) => {
-                assert!(false, e.msg.to_string());
+                assert!(false, "{}", e);
             }
         }
     }
@@ -261,12 +327,12 @@ mod tests {
                         assert_eq!(res, true)
                     }
                     Err(e) => {
-                        assert!(false, e.msg.to_string());
+                        assert!(false, "{}", e);
                     }
                 }
             }
             Err(e) => {
-                assert!(false, e.msg.to_string());
+                assert!(false, "{}", e);
             }
         }
     }
@@ -280,12 +346,12 @@ mod tests {
                         assert_eq!(res, true)
                     }
                     Err(e) => {
-                        assert!(false, e.msg.to_string());
+                        assert!(false, "{}", e);
                     }
                 }
             }
             Err(e) => {
-                assert!(false, e.msg.to_string());
+                assert!(false, "{}", e);
             }
         }
 
@@ -296,13 +362,25 @@ mod tests {
                         assert_eq!(res, true)
                     }
                     Err(e) => {
-                        assert!(false, e.msg.to_string());
+                        assert!(false, "{}", e);
                     }
                 }
             }
             Err(e) => {
-                assert!(false, e.msg.to_string());
+                assert!(false, "{}", e);
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_pin_errors() {
+        let key = "e0f4543f3e2a2c5ffc7e5e5a222e3e4d";
+
+        assert!(matches!(generate_pin_block(&ISO4, "1234", "4111111111111111", key), Err(PinError::UnsupportedFormat(ISO4))));
+        assert!(matches!(generate_pin_block(&ISO0, "12a4", "4111111111111111", key), Err(PinError::InvalidPin(m)) if m == "should only contain digits"));
+        assert!(matches!(generate_pin_block(&ISO0, "123", "4111111111111111", key), Err(PinError::InvalidPin(m)) if m == "3 digit(s), should be 4 to 12"));
+        assert!(matches!(generate_pin_block(&ISO0, "1234", "41111111", key), Err(PinError::InvalidPan(_))));
+        assert!(matches!(generate_pin_block(&ISO0, "1234", "4111111111111111", "e0f4"), Err(PinError::Crypto(CryptoError::InvalidKey(_)))));
+        assert!(matches!(verify_pin(&ISO1, "1234", &vec![0; 4], "4111111111111111", key), Err(PinError::InvalidPinBlock(_))));
+    }
+}
@@ -1,11 +1,12 @@
 //! This module provides implementation of types for handling ISO bitmaps and Bitmapped fields
 use std::collections::HashMap;
-use std::io::{BufRead};
+use std::io::Cursor;
 
 use byteorder::ByteOrder;
 
-use crate::iso8583::field::{Encoding, Field, ParseError};
+use crate::iso8583::field::{ascii_to_ebcdic, ebcdic_to_ascii, Encoding, Field, ParseError, read_data};
 use crate::iso8583::{iso_spec, IsoError};
+use crate::iso8583::trace::ParseTrace;
 
 /// This struct represents a bitmap that can support 192 (64*3) fields
 #[derive(Debug)]
@@ -15,7 +16,8 @@ pub struct Bitmap {
     t_bmp: u64,
 }
 
-//const high_bit: u64 = (0x01 as u64) << 63;
+// The high bit of a bitmap, which indicates the presence of the next bitmap
+const SECONDARY_IND: u64 = 0x8000000000000000;
 
 /// Operations on bitmap
 impl Bitmap {
@@ -28,9 +30,11 @@ impl Bitmap {
         }
     }
 
-    // Create a Bitmap from a Vec<u8>
-    pub fn from_vec(bmp_data: &Vec<u8>) -> Bitmap {
-        assert!(bmp_data.len() >= 8 && bmp_data.len() <= 24);
+    /// Create a Bitmap from a Vec<u8> of 8, 16 or 24 bytes, returns a IsoError for any other length
+    pub fn from_vec(bmp_data: &Vec<u8>) -> Result<Bitmap, IsoError> {
+        if bmp_data.len() != 8 && bmp_data.len() != 16 && bmp_data.len() != 24 {
+            return Err(IsoError::InvalidBitmap(bmp_data.len()));
+        }
         let mut b1: u64 = 0;
         let mut b2: u64 = 0;
         let mut b3: u64 = 0;
@@ -45,7 +49,7 @@ impl Bitmap {
         if bmp_data.len() >= 24 {
             b3 = byteorder::BigEndian::read_u64(&bmp_data[16..]);
         }
-        Bitmap::new(b1, b2, b3)
+        Ok(Bitmap::new(b1, b2, b3))
     }
 
     /// Returns a boolean to indicate if the specified 'pos' is turned on in the bitmap
@@ -80,21 +84,58 @@ impl Bitmap {
         }
     }
 
+    /// Turns off the position in the bitmap. The indicator for the secondary (tertiary) bitmap is turned off as well
+    /// when no position in it remains on, turning off position 1 (65) clears the secondary (tertiary) bitmap
+    pub fn set_off(&mut self, pos: u32) {
+        assert!(pos > 0 && pos <= 192);
+
+        if pos == 1 {
+            self.s_bmp = 0;
+            self.t_bmp = 0;
+        } else if pos == 65 {
+            self.t_bmp = 0;
+        }
+
+        if pos < 65 {
+            self.p_bmp &= !(SECONDARY_IND >> (pos - 1) as u64);
+        } else if pos < 129 {
+            self.s_bmp &= !(SECONDARY_IND >> (pos - 64 - 1) as u64);
+        } else {
+            self.t_bmp &= !(SECONDARY_IND >> (pos - 128 - 1) as u64);
+        }
+
+        if self.t_bmp == 0 {
+            self.s_bmp &= !SECONDARY_IND;
+        }
+        if self.s_bmp == 0 {
+            self.p_bmp &= !SECONDARY_IND;
+        }
+    }
+
+    /// Returns an iterator over the positions that are on, in ascending order. Positions 1 and 65
+    /// (the secondary and tertiary bitmap indicators) are not included
+    pub fn iter(&self) -> impl Iterator<Item=u32> + '_ {
+        (2..193).filter(move |pos| *pos != 65 && self.is_on(*pos))
+    }
+
     /// Returns the bitmap as a hexadecimal string
     pub fn hex_string(&self) -> String {
         format!("{:016.0x}{:016.0x}{:016.0x}", self.p_bmp, self.s_bmp, self.t_bmp)
     }
 
-    /// Returns the bitmap as a Vec<u8>
+    /// Returns the bitmap as a Vec<u8>. The secondary (and tertiary) bitmap is only included if any position
+    /// in it is on
     pub fn as_vec(&self) -> Vec<u8> {
-        let mut bmp_data = vec![0; 8];
+        let t_needed = self.t_bmp != 0;
+        let s_needed = t_needed || (self.s_bmp & !SECONDARY_IND) != 0;
 
-        byteorder::BigEndian::write_u64(&mut bmp_data[0..], self.p_bmp);
-        if ((self.p_bmp >> 63) & 0x01) == 0x01 {
+        let mut bmp_data = vec![0; 8];
+        byteorder::BigEndian::write_u64(&mut bmp_data[0..], if s_needed { self.p_bmp | SECONDARY_IND } else { self.p_bmp & !SECONDARY_IND });
+        if s_needed {
             bmp_data.resize(16, 0);
-            byteorder::BigEndian::write_u64(&mut bmp_data[8..], self.s_bmp);
+            byteorder::BigEndian::write_u64(&mut bmp_data[8..], if t_needed { self.s_bmp | SECONDARY_IND } else { self.s_bmp & !SECONDARY_IND });
         }
-        if ((self.s_bmp >> 63) & 0x01) == 0x01 {
+        if t_needed {
             bmp_data.resize(24, 0);
             byteorder::BigEndian::write_u64(&mut bmp_data[16..], self.t_bmp);
         }
@@ -106,6 +147,21 @@ impl Bitmap {
 #[cfg(test)]
 mod tests {
     use crate::iso8583::bitmap::Bitmap;
+    use crate::iso8583::builder::SpecBuilder;
+    use crate::iso8583::field::Encoding;
+    use crate::iso8583::field::Encoding::{ASCII, BINARY, EBCDIC};
+    use crate::iso8583::field::ParseError;
+    use crate::iso8583::iso_spec::{new_msg, Spec};
+
+    fn hex_bmp_spec(encoding: Encoding) -> Spec {
+        SpecBuilder::new("HexBitmap")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("1100", |m| m.fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", encoding, |b| b.fixed(3, "proc_code", 6, ASCII)
+                    .fixed(70, "f70", 3, ASCII)
+                    .fixed(130, "f130", 2, ASCII)))
+            .build().unwrap()
+    }
 
     #[test]
     fn test_bmp() {
@@ -127,6 +183,78 @@ mod tests {
         assert_eq!(bmp.is_on(133), true);
         assert_eq!(bmp.is_on(6), true);
     }
+
+    #[test]
+    fn test_bmp_set_off_and_iter() {
+        let mut bmp = Bitmap::new(0, 0, 0);
+
+        bmp.set_on(4);
+        bmp.set_on(99);
+        bmp.set_on(133);
+        assert_eq!(bmp.iter().collect::<Vec<u32>>(), vec![4, 99, 133]);
+        assert_eq!(bmp.as_vec().len(), 24);
+
+        bmp.set_off(133);
+        assert!(!bmp.is_on(65));
+        assert!(bmp.is_on(1));
+        assert_eq!(bmp.as_vec().len(), 16);
+
+        bmp.set_off(99);
+        assert!(!bmp.is_on(1));
+        assert_eq!(bmp.iter().collect::<Vec<u32>>(), vec![4]);
+        assert_eq!(hex::encode(bmp.as_vec()), "1000000000000000");
+
+        bmp.set_off(4);
+        assert_eq!(bmp.iter().count(), 0);
+
+        // the indicators alone don't require the secondary/tertiary bitmaps
+        let bmp = Bitmap::new(0x9000000000000000, 0x8000000000000000, 0);
+        assert_eq!(hex::encode(bmp.as_vec()), "1000000000000000");
+
+        let mut bmp = Bitmap::new(0, 0, 0);
+        bmp.set_on(70);
+        bmp.set_on(150);
+        bmp.set_off(1);
+        assert_eq!(bmp.iter().count(), 0);
+        assert_eq!(bmp.as_vec().len(), 8);
+    }
+
+    #[test]
+    fn test_hex_bitmaps() {
+        for (encoding, bmp_len, first_bmp) in vec![(BINARY, 24, vec![0xa0, 0, 0, 0, 0, 0, 0, 0]),
+                                                    (ASCII, 48, b"A000000000000000".to_vec()),
+                                                    (EBCDIC, 48, vec![0xc1, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0])] {
+            let spec = hex_bmp_spec(encoding);
+
+            let mut iso_msg = new_msg(&spec, spec.get_message("1100").unwrap());
+            iso_msg.set("message_type", "1100").unwrap();
+            iso_msg.set_on(3, "004000").unwrap();
+            iso_msg.set_on(70, "301").unwrap();
+            iso_msg.set_on(130, "99").unwrap();
+
+            let data = iso_msg.assemble().unwrap();
+            assert_eq!(data.len(), 4 + bmp_len + 6 + 3 + 2);
+            assert_eq!(&data[4..4 + first_bmp.len()], first_bmp.as_slice());
+
+            let parsed = spec.parse_strict(&data).unwrap();
+            assert_eq!(parsed.bmp.iter().collect::<Vec<u32>>(), vec![3, 70, 130]);
+            assert_eq!(parsed.bmp_child_value(70).unwrap(), "301");
+            assert_eq!(parsed.bmp_child_value(130).unwrap(), "99");
+        }
+
+        // only the primary bitmap
+        let spec = hex_bmp_spec(ASCII);
+        let parsed = spec.parse_strict(b"11002000000000000000004000").unwrap();
+        assert_eq!(parsed.bmp_child_value(3).unwrap(), "004000");
+
+        match spec.parse(b"11002000000000G00000004000") {
+            Err(ParseError::InvalidEncoding { field, offset }) => {
+                assert_eq!(field, "bitmap");
+                assert_eq!(offset, 4);
+            }
+            _ => panic!("expected an invalid encoding error")
+        }
+    }
 }
 
 
@@ -140,6 +268,43 @@ pub struct BmpField {
 
 /// Operarions on BmpField
 impl BmpField {
+    /// Reads a single (64 bit) bitmap from in_buf and appends the bytes read to raw. A BINARY (or BCD)
+    /// bitmap is 8 bytes and a ASCII or EBCDIC bitmap is 16 hex characters
+    fn read_bmp(&self, in_buf: &mut Cursor<&[u8]>, raw: &mut Vec<u8>) -> Result<u64, ParseError> {
+        let offset = in_buf.position() as usize;
+
+        let bmp_data = match self.encoding {
+            Encoding::ASCII | Encoding::EBCDIC => {
+                let data = read_data(in_buf, 16, &self.name)?;
+                raw.extend(&data);
+                let hex_str = match self.encoding {
+                    Encoding::EBCDIC => ebcdic_to_ascii(&data),
+                    _ => String::from_utf8_lossy(&data).to_string()
+                };
+                match hex::decode(hex_str) {
+                    Ok(bmp_data) => bmp_data,
+                    Err(_) => return Err(ParseError::InvalidEncoding { field: self.name.clone(), offset })
+                }
+            }
+            _ => {
+                let data = read_data(in_buf, 8, &self.name)?;
+                raw.extend(&data);
+                data
+            }
+        };
+
+        Ok(byteorder::BigEndian::read_u64(bmp_data.as_slice()))
+    }
+
+    /// Returns the state of the bitmap in iso_msg. The state of the top level bitmap is IsoMsg::bmp while
+    /// the state of a nested bitmap (like the one within DE127) is kept as the value of the field
+    pub fn bitmap(&self, iso_msg: &iso_spec::IsoMsg) -> Bitmap {
+        if self.name == "bitmap" {
+            return Bitmap::new(iso_msg.bmp.p_bmp, iso_msg.bmp.s_bmp, iso_msg.bmp.t_bmp);
+        }
+        iso_msg.fd_map.get(&self.name).and_then(|data| Bitmap::from_vec(data).ok()).unwrap_or_else(|| Bitmap::new(0, 0, 0))
+    }
+
     /// Returns a field at the position (if defined or a IsoError if not)
     pub fn by_position(&self, pos: u32) -> Result<&Box<dyn Field>, IsoError> {
         let opt = &(self.children).iter().filter(|f| -> bool{
@@ -152,7 +317,7 @@ impl BmpField {
 
         match opt {
             Some(f) => Ok(f),
-            None => Err(IsoError { msg: format!("position {} not defined", pos) }),
+            None => Err(IsoError::FieldNotFound(format!("position {}", pos))),
         }
     }
 }
@@ -163,110 +328,66 @@ impl Field for BmpField {
         &self.name
     }
 
-    fn parse(&self, in_buf: &mut dyn BufRead, f2d_map: &mut HashMap<String, Vec<u8>>) -> Result<(), ParseError> {
-        let mut f_data = vec![0; 8];
-
-        match in_buf.read_exact(&mut f_data[..]) {
-            Ok(_) => {
-                let b1 = byteorder::BigEndian::read_u64(f_data.as_slice());
-                let mut b2: u64 = 0;
-                let mut b3: u64 = 0;
-
-                if f_data[0] & 0x80 == 0x80 {
-                    let mut s_bmp_data = vec![0; 8];
-                    match in_buf.read_exact(&mut s_bmp_data[..]) {
-                        Ok(_) => {
-                            trace!("parsed sec...");
-                            b2 = byteorder::BigEndian::read_u64(s_bmp_data.as_slice());
-                            if s_bmp_data[0] & 0x80 == 0x80 {
-                                let mut t_bmp_data = vec![0; 8];
-                                match in_buf.read_exact(&mut t_bmp_data[..]) {
-                                    Ok(_) => {
-                                        trace!("parsed tertiary...");
-                                        b3 = byteorder::BigEndian::read_u64(t_bmp_data.as_slice());
-                                    }
-                                    Err(_) => {
-                                        return Err(ParseError { msg: format!("failed to parse tertiary bitmap - {}", self.name) });
-                                    }
-                                }
-                            }
-                        }
-                        Err(_) => {
-                            return Err(ParseError { msg: format!("failed to secondary parse - {}", self.name) });
-                        }
-                    }
-                }
+    fn parse(&self, in_buf: &mut Cursor<&[u8]>, f2d_map: &mut HashMap<String, Vec<u8>>, trace: &mut ParseTrace) -> Result<(), ParseError> {
+        let offset = in_buf.position() as usize;
+        let mut f_data = vec![];
+        let b1 = self.read_bmp(in_buf, &mut f_data)?;
+        let mut b2: u64 = 0;
+        let mut b3: u64 = 0;
 
+        if b1 & SECONDARY_IND == SECONDARY_IND {
+            b2 = self.read_bmp(in_buf, &mut f_data)?;
+            trace!("parsed sec...");
+            if b2 & SECONDARY_IND == SECONDARY_IND {
+                b3 = self.read_bmp(in_buf, &mut f_data)?;
+                trace!("parsed tertiary...");
+            }
+        }
 
-                let bmp = Bitmap::new(b1, b2, b3);
-                f2d_map.insert(self.name().to_string(), bmp.as_vec());
 
+        let bmp = Bitmap::new(b1, b2, b3);
+        trace.record_value(self, offset, &[], &f_data, hex::encode(bmp.as_vec()));
+        f2d_map.insert(self.name().to_string(), bmp.as_vec());
 
-                trace!("parsed-data: {} := {}", self.name, bmp.hex_string());
 
+        trace!("parsed-data: {} := {}", self.name, bmp.hex_string());
 
-                for i in 2..193 {
-                    if bmp.is_on(i) {
-                        if i == 1 || i == 65 {
-                            continue;
-                        }
 
-                        let is_present = self.by_position(i);
-                        match match is_present {
-                            Ok(f) => {
-                                debug!("parsing field - {}", f.name());
-                                match f.parse(in_buf, f2d_map) {
-                                    Ok(_) => {
-                                        Ok(())
-                                    }
-                                    Err(e) => Err(e),
-                                }
-                            }
-                            Err(e) => Err(ParseError { msg: e.msg }),
-                        }
-                        {
-                            Err(e) => {
-                                return Err(e);
-                            }
-                            _ => {}
-                        }
-                    }
+        for i in bmp.iter() {
+            match self.by_position(i) {
+                Ok(f) => {
+                    debug!("parsing field - {}", f.name());
+                    f.parse(in_buf, f2d_map, trace)?;
+                }
+                Err(_) => {
+                    return Err(ParseError::UndefinedBitmapPosition { field: self.name.clone(), offset: in_buf.position() as usize, position: i });
                 }
-                Ok(())
-            }
-            Err(_) => {
-                Err(ParseError { msg: format!("failed to parse primary bitmap - {}", self.name) })
             }
         }
+        Ok(())
     }
 
 
     fn assemble(&self, out_buf: &mut Vec<u8>, iso_msg: &iso_spec::IsoMsg) -> Result<u32, ParseError> {
-        let bmp_data = iso_msg.bmp.as_vec();
-        out_buf.extend(bmp_data);
-
-        for pos in 2..193 {
-            if iso_msg.bmp.is_on(pos) {
-                if pos == 1 || pos == 65 {
-                    continue;
-                }
+        let bmp = self.bitmap(iso_msg);
+        let bmp_data = bmp.as_vec();
+        match self.encoding {
+            Encoding::ASCII => out_buf.extend(hex::encode_upper(bmp_data).into_bytes()),
+            Encoding::EBCDIC => out_buf.extend(ascii_to_ebcdic(&mut hex::encode_upper(bmp_data).into_bytes())),
+            _ => out_buf.extend(bmp_data)
+        }
 
-                match self.by_position(pos) {
-                    Ok(f) => {
-                        match iso_msg.fd_map.get(f.name()) {
-                            Some(_) => {
-                                match f.assemble(out_buf, iso_msg) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        return Err(ParseError { msg: format!("failed to assemble field {}, {}", f.name(), e.msg) });
-                                    }
-                                }
-                            }
-                            None => { return Err(ParseError { msg: format!("position {} is on, but no field data present!", pos) }); }
-                        };
-                    }
-                    Err(e) => return Err(ParseError { msg: e.msg })
+        for pos in bmp.iter() {
+            match self.by_position(pos) {
+                Ok(f) => {
+                    match iso_msg.fd_map.get(f.name()) {
+                        Some(_) => {
+                            f.assemble(out_buf, iso_msg)?;
+                        }
+                        None => { return Err(ParseError::MissingFieldData { field: f.name().clone() }); }
+                    };
                 }
+                Err(_) => return Err(ParseError::UndefinedBitmapPosition { field: self.name.clone(), offset: out_buf.len(), position: pos })
             }
         };
 
@@ -282,24 +403,12 @@ impl Field for BmpField {
     }
 
 
-    fn child_by_pos(&self, pos: u32) -> &dyn Field {
-        self.children.iter().find(|f| -> bool {
-            if f.position() == pos {
-                true
-            } else {
-                false
-            }
-        }).unwrap().as_ref()
+    fn child_by_pos(&self, pos: u32) -> Option<&dyn Field> {
+        self.children.iter().find(|f| f.position() == pos).map(|f| f.as_ref())
     }
 
-    fn child_by_name(&self, name: &String) -> &dyn Field {
-        self.children.iter().find(|f| -> bool {
-            if f.name() == name {
-                true
-            } else {
-                false
-            }
-        }).unwrap().as_ref()
+    fn child_by_name(&self, name: &String) -> Option<&dyn Field> {
+        self.children.iter().find(|f| f.name() == name).map(|f| f.as_ref())
     }
 
     fn to_string(&self, data: &Vec<u8>) -> String {
@@ -309,4 +418,8 @@ impl Field for BmpField {
     fn to_raw(&self, _val: &str) -> Vec<u8> {
         unimplemented!()
     }
+
+    fn as_bmp(&self) -> Option<&BmpField> {
+        Some(self)
+    }
 }
\ No newline at end of file
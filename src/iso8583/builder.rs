@@ -0,0 +1,524 @@
+//! This module contains a builder to define a specification in code (as an alternative to a YAML file)
+//!
+//! ```
+//! use iso8583_rs::iso8583::builder::SpecBuilder;
+//! use iso8583_rs::iso8583::field::Encoding::{ASCII, BINARY};
+//!
+//! let spec = SpecBuilder::new("Visa")
+//!     .header(|h| h.fixed("message_type", 4, ASCII))
+//!     .message("1100", |m| m.selector(&["1100", "1110"])
+//!         .fixed("message_type", 4, ASCII)
+//!         .bitmap("bitmap", BINARY, |b| b.var(2, "pan", 2, ASCII, ASCII)
+//!             .fixed(3, "proc_code", 6, ASCII)
+//!             .fixed(4, "amount", 12, ASCII)))
+//!     .build()
+//!     .unwrap();
+//!
+//! assert_eq!(spec.name(), "Visa");
+//! ```
+use crate::iso8583::field::{BcdPadding, ContentType, Encoding};
+use crate::iso8583::iso_spec::{Spec, SpecError};
+use crate::iso8583::rules::Presence;
+use crate::iso8583::defaults::Generator;
+use crate::iso8583::tlv::TlvFormat;
+use crate::iso8583::yaml_de::{YField, YMessageSegment, YSpec, YTlvFormat};
+
+/// This struct is used to build a Spec
+pub struct SpecBuilder {
+    name: String,
+    id: u32,
+    header_fields: Vec<YField>,
+    messages: Vec<YMessageSegment>,
+    lenient: Option<bool>,
+}
+
+/// This struct is used to build a list of fields (header fields or fields of a message)
+pub struct FieldsBuilder {
+    fields: Vec<YField>,
+}
+
+/// This struct is used to build a message segment
+pub struct MessageBuilder {
+    name: String,
+    id: Option<u32>,
+    selector: Vec<String>,
+    fields: FieldsBuilder,
+}
+
+/// This struct is used to build the children (positions) of a bitmapped field
+pub struct BitmapBuilder {
+    children: Vec<YField>,
+}
+
+pub(in crate::iso8583) fn new_field(name: &str, id: usize, field_type: &str, len: u32, len_encoding: Option<Encoding>, encoding: Encoding, position: Option<u32>) -> YField {
+    YField {
+        name: name.to_string(),
+        id: id as u32,
+        len,
+        field_type: field_type.to_string(),
+        len_encoding,
+        data_encoding: encoding,
+        position,
+        children: None,
+        tlv_format: None,
+        bcd_padding: None,
+        len_unit: None,
+        pad_char: None,
+        justify: None,
+        overflow: None,
+        content: None,
+        min_len: None,
+        max_len: None,
+        lenient: None,
+        presence: None,
+        when: None,
+        default: None,
+        generator: None,
+        echo: None,
+    }
+}
+
+impl SpecBuilder {
+    /// Creates a new SpecBuilder for a spec with the given name
+    pub fn new(name: &str) -> SpecBuilder {
+        SpecBuilder {
+            name: name.to_string(),
+            id: 1,
+            header_fields: vec![],
+            messages: vec![],
+            lenient: None,
+        }
+    }
+
+    /// Sets the id of the spec
+    pub fn id(mut self, id: u32) -> SpecBuilder {
+        self.id = id;
+        self
+    }
+
+    /// Sets whether content rule violations are only logged (instead of failing set and parse)
+    pub fn lenient(mut self, lenient: bool) -> SpecBuilder {
+        self.lenient = Some(lenient);
+        self
+    }
+
+    /// Defines the header fields of the spec, the values of which are used to select the message segment
+    pub fn header<F>(mut self, f: F) -> SpecBuilder where F: FnOnce(FieldsBuilder) -> FieldsBuilder {
+        self.header_fields.extend(f(FieldsBuilder { fields: vec![] }).fields);
+        self
+    }
+
+    /// Defines a message segment with the given name. Unless selectors are defined on the segment, the name
+    /// is used as the selector
+    pub fn message<F>(mut self, name: &str, f: F) -> SpecBuilder where F: FnOnce(MessageBuilder) -> MessageBuilder {
+        let msg = f(MessageBuilder {
+            name: name.to_string(),
+            id: None,
+            selector: vec![],
+            fields: FieldsBuilder { fields: vec![] },
+        });
+
+        let selector = if msg.selector.is_empty() { vec![msg.name.clone()] } else { msg.selector };
+        self.messages.push(YMessageSegment {
+            name: msg.name,
+            id: msg.id.unwrap_or(self.messages.len() as u32 + 1),
+            selector,
+            fields: msg.fields.fields,
+        });
+        self
+    }
+
+    /// Builds and returns the Spec or a SpecError with all problems found if the definition is not valid
+    pub fn build(self) -> Result<Spec, SpecError> {
+        YSpec {
+            name: self.name,
+            id: self.id,
+            messages: self.messages,
+            header_fields: self.header_fields,
+            lenient: self.lenient,
+        }.into_spec(None)
+    }
+}
+
+impl FieldsBuilder {
+    /// Adds a fixed field of len bytes
+    pub fn fixed(mut self, name: &str, len: u32, encoding: Encoding) -> FieldsBuilder {
+        self.fields.push(new_field(name, self.fields.len() + 1, "Fixed", len, None, encoding, None));
+        self
+    }
+
+    /// Adds a variable field whose length indicator is len bytes long
+    pub fn var(mut self, name: &str, len: u32, len_encoding: Encoding, encoding: Encoding) -> FieldsBuilder {
+        self.fields.push(new_field(name, self.fields.len() + 1, "Variable", len, Some(len_encoding), encoding, None));
+        self
+    }
+
+    /// Adds a bitmapped field whose children are defined by f
+    pub fn bitmap<F>(mut self, name: &str, encoding: Encoding, f: F) -> FieldsBuilder where F: FnOnce(BitmapBuilder) -> BitmapBuilder {
+        let mut field = new_field(name, self.fields.len() + 1, "Bitmapped", 0, None, encoding, None);
+        field.children = Some(f(BitmapBuilder { children: vec![] }).children);
+        self.fields.push(field);
+        self
+    }
+}
+
+impl MessageBuilder {
+    /// Sets the id of the segment
+    pub fn id(mut self, id: u32) -> MessageBuilder {
+        self.id = Some(id);
+        self
+    }
+
+    /// Sets the header values that select this segment
+    pub fn selector(mut self, selector: &[&str]) -> MessageBuilder {
+        self.selector = selector.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Adds a fixed field of len bytes
+    pub fn fixed(mut self, name: &str, len: u32, encoding: Encoding) -> MessageBuilder {
+        self.fields = self.fields.fixed(name, len, encoding);
+        self
+    }
+
+    /// Adds a variable field whose length indicator is len bytes long
+    pub fn var(mut self, name: &str, len: u32, len_encoding: Encoding, encoding: Encoding) -> MessageBuilder {
+        self.fields = self.fields.var(name, len, len_encoding, encoding);
+        self
+    }
+
+    /// Sets the default value (filled in new messages) of the last added field
+    pub fn default_value(mut self, val: &str) -> MessageBuilder {
+        if let Some(field) = self.fields.fields.last_mut() {
+            field.default = Some(val.to_string());
+        }
+        self
+    }
+
+    /// Adds a bitmapped field whose children are defined by f
+    pub fn bitmap<F>(mut self, name: &str, encoding: Encoding, f: F) -> MessageBuilder where F: FnOnce(BitmapBuilder) -> BitmapBuilder {
+        self.fields = self.fields.bitmap(name, encoding, f);
+        self
+    }
+}
+
+impl BitmapBuilder {
+    /// Adds a fixed field of len bytes at position pos
+    pub fn fixed(mut self, pos: u32, name: &str, len: u32, encoding: Encoding) -> BitmapBuilder {
+        self.children.push(new_field(name, pos as usize, "Fixed", len, None, encoding, Some(pos)));
+        self
+    }
+
+    /// Adds a variable field at position pos whose length indicator is len bytes long
+    pub fn var(mut self, pos: u32, name: &str, len: u32, len_encoding: Encoding, encoding: Encoding) -> BitmapBuilder {
+        self.children.push(new_field(name, pos as usize, "Variable", len, Some(len_encoding), encoding, Some(pos)));
+        self
+    }
+
+    /// Adds a fixed BCD field of len digits at position pos, a odd number of digits is padded as per padding
+    pub fn fixed_bcd(mut self, pos: u32, name: &str, len: u32, padding: BcdPadding) -> BitmapBuilder {
+        let mut field = new_field(name, pos as usize, "Fixed", len, None, Encoding::BCD, Some(pos));
+        field.bcd_padding = Some(padding);
+        self.children.push(field);
+        self
+    }
+
+    /// Adds a variable BCD field at position pos whose length indicator is len bytes long and holds the number of
+    /// digits, a odd number of digits is padded as per padding
+    pub fn var_bcd(mut self, pos: u32, name: &str, len: u32, len_encoding: Encoding, padding: BcdPadding) -> BitmapBuilder {
+        let mut field = new_field(name, pos as usize, "Variable", len, Some(len_encoding), Encoding::BCD, Some(pos));
+        field.bcd_padding = Some(padding);
+        self.children.push(field);
+        self
+    }
+
+    /// Adds a field at position pos whose length indicator is len bytes long and whose data is a list of TLV data
+    /// elements in the given format
+    pub fn tlv(mut self, pos: u32, name: &str, len: u32, len_encoding: Encoding, encoding: Encoding, format: TlvFormat) -> BitmapBuilder {
+        let field = match format {
+            TlvFormat::Ber => new_field(name, pos as usize, "Tlv", len, Some(len_encoding), encoding, Some(pos)),
+            TlvFormat::Ascii { tag_len, tag_encoding, len_len, len_encoding: tlv_len_encoding } => {
+                let mut field = new_field(name, pos as usize, "AsciiTlv", len, Some(len_encoding), encoding, Some(pos));
+                field.tlv_format = Some(YTlvFormat { tag_len, tag_encoding, len_len, len_encoding: tlv_len_encoding });
+                field
+            }
+        };
+        self.children.push(field);
+        self
+    }
+
+    /// Adds a bitmapped field (without a position) whose children are defined by f, this is used to nest a
+    /// bitmap within a variable field (like DE127)
+    pub fn bitmap<F>(mut self, name: &str, encoding: Encoding, f: F) -> BitmapBuilder where F: FnOnce(BitmapBuilder) -> BitmapBuilder {
+        let mut field = new_field(name, self.children.len() + 1, "Bitmapped", 0, None, encoding, None);
+        field.children = Some(f(BitmapBuilder { children: vec![] }).children);
+        self.children.push(field);
+        self
+    }
+
+    /// Sets the content type and the min and max length of the last added field
+    pub fn content(mut self, content: ContentType, min_len: Option<u32>, max_len: Option<u32>) -> BitmapBuilder {
+        if let Some(field) = self.children.last_mut() {
+            field.content = Some(content);
+            field.min_len = min_len;
+            field.max_len = max_len;
+        }
+        self
+    }
+
+    /// Sets the presence of the last added field, when is the condition of a conditional field (see FieldRule::when)
+    pub fn presence(mut self, presence: Presence, when: Option<&str>) -> BitmapBuilder {
+        if let Some(field) = self.children.last_mut() {
+            field.presence = Some(presence);
+            field.when = when.map(|w| w.to_string());
+        }
+        self
+    }
+
+    /// Sets the default value (filled in new messages) of the last added field
+    pub fn default_value(mut self, val: &str) -> BitmapBuilder {
+        if let Some(field) = self.children.last_mut() {
+            field.default = Some(val.to_string());
+        }
+        self
+    }
+
+    /// Sets the generator of the value (filled in new messages) of the last added field
+    pub fn generator(mut self, generator: Generator) -> BitmapBuilder {
+        if let Some(field) = self.children.last_mut() {
+            field.generator = Some(generator);
+        }
+        self
+    }
+
+    /// Marks the last added field to be copied from the request into a response (see IsoMsg::response)
+    pub fn echo(mut self) -> BitmapBuilder {
+        if let Some(field) = self.children.last_mut() {
+            field.echo = Some(true);
+        }
+        self
+    }
+
+    /// Adds a fixed field of len bytes at position pos whose subfields are defined by f
+    pub fn fixed_composite<F>(mut self, pos: u32, name: &str, len: u32, encoding: Encoding, f: F) -> BitmapBuilder where F: FnOnce(BitmapBuilder) -> BitmapBuilder {
+        let mut field = new_field(name, pos as usize, "Fixed", len, None, encoding, Some(pos));
+        field.children = Some(f(BitmapBuilder { children: vec![] }).children);
+        self.children.push(field);
+        self
+    }
+
+    /// Adds a variable field at position pos whose length indicator is len bytes long and whose subfields are defined by f
+    pub fn var_composite<F>(mut self, pos: u32, name: &str, len: u32, len_encoding: Encoding, encoding: Encoding, f: F) -> BitmapBuilder where F: FnOnce(BitmapBuilder) -> BitmapBuilder {
+        let mut field = new_field(name, pos as usize, "Variable", len, Some(len_encoding), encoding, Some(pos));
+        field.children = Some(f(BitmapBuilder { children: vec![] }).children);
+        self.children.push(field);
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::builder::SpecBuilder;
+    use crate::iso8583::field::{BcdPadding, ContentError, ParseError};
+    use crate::iso8583::field::Encoding::{ASCII, BCD, BINARY, EBCDIC};
+    use crate::iso8583::iso_spec::new_msg;
+    use crate::iso8583::IsoError;
+
+    #[test]
+    fn test_build_spec() {
+        let spec = SpecBuilder::new("TestSpec").id(10)
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("1100", |m| m.selector(&["1100", "1110"])
+                .fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.var(2, "pan", 2, ASCII, ASCII)
+                    .fixed(4, "amount", 12, ASCII)
+                    .var(62, "private_2", 1, BINARY, EBCDIC)
+                    .var(63, "private_3", 2, BCD, ASCII)))
+            .message("0800", |m| m.fixed("message_type", 4, ASCII))
+            .build().unwrap();
+
+        assert_eq!(spec.name(), "TestSpec");
+        assert_eq!(spec.id(), 10);
+        assert!(spec.get_message_from_header("0800").is_ok());
+
+        let seg = spec.get_message_from_header("1110").unwrap();
+        assert_eq!(seg.name(), "1100");
+
+        let mut iso_msg = new_msg(&spec, seg);
+        iso_msg.set("message_type", "1110").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(4, "000000000100").unwrap();
+        iso_msg.set_on(62, "private").unwrap();
+        iso_msg.set_on(63, "007").unwrap();
+
+        let data = iso_msg.assemble().unwrap();
+        let parsed = spec.parse(&data).unwrap();
+        assert_eq!(parsed.bmp_child_value(2).unwrap(), "4567909845671235");
+        assert_eq!(parsed.bmp_child_value(62).unwrap(), "private");
+        assert_eq!(parsed.bmp_child_value(63).unwrap(), "007");
+    }
+
+    #[test]
+    fn test_build_composite_fields() {
+        let spec = SpecBuilder::new("TestSpec")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("1100", |m| m.fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.fixed(4, "amount", 12, ASCII)
+                    .var_composite(48, "additional_data", 3, ASCII, ASCII, |s| s.fixed(1, "terminal_type", 2, ASCII)
+                        .fixed(2, "channel", 1, ASCII)
+                        .var(3, "merchant_id", 2, ASCII, ASCII))
+                    .fixed_composite(61, "private_1", 10, ASCII, |s| s.fixed(1, "store", 4, ASCII)
+                        .fixed(2, "lane", 6, ASCII))))
+            .build().unwrap();
+
+        let seg = spec.get_message_from_header("1100").unwrap();
+        assert_eq!(seg.field_path("48.3").unwrap().iter().map(|f| f.name().as_str()).collect::<Vec<&str>>(),
+                   vec!["additional_data", "merchant_id"]);
+        assert_eq!(seg.field_path("private_1.lane").unwrap().len(), 2);
+        assert_eq!(seg.field_path("lane").unwrap().len(), 2);
+        assert!(seg.field_path("48.4").is_err());
+
+        let mut iso_msg = new_msg(&spec, seg);
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(4, "000000000100").unwrap();
+        iso_msg.set_on(48, "05E07MERCH01").unwrap();
+        assert_eq!(iso_msg.get("48.3").unwrap(), "MERCH01");
+        assert_eq!(iso_msg.get("channel").unwrap(), "E");
+
+        // setting a subfield rebuilds the parent
+        iso_msg.set("48.3", "M2").unwrap();
+        assert_eq!(iso_msg.get("48").unwrap(), "05E02M2");
+
+        // a value the subfields cannot be parsed from (without a channel) is rejected
+        assert!(matches!(iso_msg.set_on(48, "0507MERCH01"), Err(IsoError::Parse(ParseError::BadLengthIndicator { .. }))));
+        assert_eq!(iso_msg.get("48").unwrap(), "05E02M2");
+        assert_eq!(iso_msg.get("channel").unwrap(), "E");
+
+        // the parent is only available (and on in the bitmap) once all of its subfields are set
+        iso_msg.set("private_1.store", "0001").unwrap();
+        assert!(iso_msg.get("61").is_err());
+        assert!(!iso_msg.bmp.is_on(61));
+        iso_msg.set("private_1.lane", "LANE07").unwrap();
+        assert_eq!(iso_msg.get("61").unwrap(), "0001LANE07");
+        assert!(iso_msg.bmp.is_on(61));
+
+        let data = iso_msg.assemble().unwrap();
+        let parsed = spec.parse(&data).unwrap();
+        assert_eq!(parsed.get("additional_data.terminal_type").unwrap(), "05");
+        assert_eq!(parsed.get("48.merchant_id").unwrap(), "M2");
+        assert_eq!(parsed.get("61.2").unwrap(), "LANE07");
+    }
+
+    #[test]
+    fn test_build_nested_bitmap() {
+        let spec = SpecBuilder::new("TestSpec")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("0200", |m| m.fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.fixed(3, "proc_code", 6, ASCII)
+                    .var_composite(127, "postilion_private", 3, ASCII, ASCII, |s| s.bitmap("bitmap_127", BINARY, |b| b
+                        .var(2, "switch_key", 2, ASCII, ASCII)
+                        .fixed(3, "routing_info", 4, ASCII)
+                        .fixed(70, "extended_data", 2, ASCII)))))
+            .build().unwrap();
+
+        let seg = spec.get_message_from_header("0200").unwrap();
+        assert_eq!(seg.field_path("127.3").unwrap().iter().map(|f| f.name().as_str()).collect::<Vec<&str>>(),
+                   vec!["postilion_private", "bitmap_127", "routing_info"]);
+
+        let mut iso_msg = new_msg(&spec, seg);
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_on(3, "000000").unwrap();
+        iso_msg.set("127.2", "KEY1").unwrap();
+        iso_msg.set("postilion_private.extended_data", "XD").unwrap();
+
+        // the nested bitmap is tracked separately from the message bitmap
+        assert_eq!(iso_msg.bmp.iter().collect::<Vec<u32>>(), vec![3, 127]);
+        assert_eq!(iso_msg.bitmap_of("127").unwrap().iter().collect::<Vec<u32>>(), vec![2, 70]);
+        assert_eq!(hex::encode(&iso_msg.fd_map["postilion_private"]),
+                   format!("c0000000000000000400000000000000{}", hex::encode("04KEY1XD")));
+
+        let data = iso_msg.assemble().unwrap();
+        let parsed = spec.parse_strict(&data).unwrap();
+        assert_eq!(parsed.get("127.2").unwrap(), "KEY1");
+        assert_eq!(parsed.get("extended_data").unwrap(), "XD");
+        assert!(parsed.get("127.3").is_err());
+        assert_eq!(parsed.bitmap_of("127.bitmap_127").unwrap().iter().collect::<Vec<u32>>(), vec![2, 70]);
+        assert!(parsed.bitmap_of("3").is_err());
+
+        // a bitmap follows the fields that are set and cannot be set itself
+        assert!(matches!(iso_msg.set("127.bitmap_127", "8000000000000000"), Err(IsoError::Other(_))));
+        assert!(matches!(iso_msg.set("bitmap", "8000000000000000"), Err(IsoError::Other(_))));
+
+        match SpecBuilder::new("TestSpec")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("0200", |m| m.fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b
+                    .fixed_composite(126, "private_126", 8, ASCII, |s| s.bitmap("bitmap_126", BINARY, |b| b))
+                    .var_composite(127, "private_127", 3, ASCII, ASCII, |s| s.fixed(1, "tag", 2, ASCII)
+                        .bitmap("bitmap_127", BINARY, |b| b))))
+            .build() {
+            Ok(_) => panic!("spec should have failed validation"),
+            Err(e) => {
+                let messages: Vec<&str> = e.diagnostics.iter().map(|d| d.message.as_str()).collect();
+                assert_eq!(messages, vec!["bitmapped fields can only be nested within a variable field",
+                                          "a bitmapped subfield should be the only subfield of a variable field"]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_bcd_fields() {
+        let spec = SpecBuilder::new("TestSpec")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("0100", |m| m.fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.var_bcd(2, "pan", 1, BCD, BcdPadding::RightF)
+                    .fixed_bcd(3, "proc_code", 6, BcdPadding::Left0)
+                    .fixed_bcd(22, "pos_entry_mode", 3, BcdPadding::Left0)
+                    .var_bcd(35, "track_2", 1, BINARY, BcdPadding::Right0)))
+            .message("0110", |m| m.fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.var(2, "pan", 1, BINARY, BINARY)))
+            .build().unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("0100").unwrap());
+        iso_msg.set("message_type", "0100").unwrap();
+        iso_msg.set_on(2, "4761739001010119005").unwrap();
+        iso_msg.set_on(3, "000000").unwrap();
+        iso_msg.set_on(22, "051").unwrap();
+        iso_msg.set_on(35, "4761739001010119005d2512201").unwrap();
+
+        let data = iso_msg.assemble().unwrap();
+        assert_eq!(hex::encode(&data[12..]), "19476173900101011900 5f000000 0051 1b4761739001010119005d25122010".replace(' ', ""));
+
+        let parsed = spec.parse_strict(&data).unwrap();
+        assert_eq!(parsed.bmp_child_value(2).unwrap(), "4761739001010119005");
+        assert_eq!(parsed.bmp_child_value(22).unwrap(), "051");
+        assert_eq!(parsed.bmp_child_value(35).unwrap(), "4761739001010119005D2512201");
+        assert_eq!(spec.parse_traced(&data).fields[2].value, "4761739001010119005");
+
+        // a odd number of digits cannot be echoed into a BINARY field
+        let mut resp = new_msg(&spec, spec.get_message_from_header("0110").unwrap());
+        assert!(matches!(resp.echo_from(&parsed, &[2]), Err(IsoError::InvalidContent { error: ContentError::InvalidHex, .. })));
+        assert!(resp.get("pan").is_err());
+
+        // only hex digits can be packed
+        iso_msg.set_on(3, "00000X").unwrap();
+        assert!(iso_msg.assemble().is_err());
+    }
+
+    #[test]
+    fn test_build_invalid_spec() {
+        match SpecBuilder::new("TestSpec")
+            .message("1100", |m| m.fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.fixed(2, "pan", 0, ASCII).var(2, "pan", 4, BCD, ASCII)))
+            .build() {
+            Ok(_) => panic!("spec should have failed validation"),
+            Err(e) => {
+                let paths: Vec<&str> = e.diagnostics.iter().map(|d| d.path.as_str()).collect();
+                assert_eq!(paths, vec!["header_fields", "messages[1100].bitmap.pan", "messages[1100].bitmap.pan",
+                                       "messages[1100].bitmap.pan", "messages[1100].bitmap.pan"]);
+                assert!(e.diagnostics.iter().all(|d| d.line.is_none()));
+            }
+        }
+    }
+}
@@ -9,17 +9,17 @@ use crate::iso8583::server::get_hexdump;
 
 
 /// This struct represents a ISO8583 TCP client
-pub struct ISOTcpClient {
+pub struct ISOTcpClient<'a> {
     server_addr: String,
     mli: Box<dyn MLI>,
-    spec: &'static Spec,
+    spec: &'a Spec,
     _tcp_stream: Option<TcpStream>,
 }
 
 
-impl ISOTcpClient {
+impl<'a> ISOTcpClient<'a> {
     /// Creates a new ISOTcpClient
-    pub fn new(server_addr: &str, spec: &'static Spec, mli_type: MLIType) -> ISOTcpClient {
+    pub fn new(server_addr: &str, spec: &'a Spec, mli_type: MLIType) -> ISOTcpClient<'a> {
         let mli: Box<dyn MLI>;
 
         match mli_type {
@@ -39,25 +39,19 @@ impl ISOTcpClient {
 
     /// Sends a ISO message to the server and returns the response from server on success
     /// or a IsoError on failure
-    pub fn send(&mut self, iso_msg: &IsoMsg) -> Result<IsoMsg, IsoError> {
-        match iso_msg.assemble() {
-            Ok(data) => {
-                let mut buf = self.mli.create(&data.len()).unwrap();
-                buf.extend(data);
-                self.send_recv(&buf)
-            }
-            Err(e) => {
-                Err(IsoError { msg: format!("Failed to assemble request message: {}", e.msg) })
-            }
-        }
+    pub fn send(&mut self, iso_msg: &IsoMsg) -> Result<IsoMsg<'a>, IsoError> {
+        let data = iso_msg.assemble()?;
+        let mut buf = self.mli.create(&data.len())?;
+        buf.extend(data);
+        self.send_recv(&buf)
     }
 
-    fn send_recv(&mut self, raw_msg: &Vec<u8>) -> Result<IsoMsg, IsoError> {
+    fn send_recv(&mut self, raw_msg: &Vec<u8>) -> Result<IsoMsg<'a>, IsoError> {
         println!("raw iso msg = {}", hex::encode(raw_msg.as_slice()));
 
         if self._tcp_stream.is_none() {
             self._tcp_stream = match TcpStream::connect(&self.server_addr) {
-                Err(e) => return Err(IsoError { msg: e.to_string() }),
+                Err(e) => return Err(IsoError::Io(e)),
                 Ok(c) => {
                     println!("connected to server @ {:?}", c.local_addr());
                     Option::Some(c)
@@ -67,8 +61,8 @@ impl ISOTcpClient {
 
         let client = self._tcp_stream.as_mut().unwrap();
 
-        client.write_all(raw_msg.as_slice()).unwrap();
-        client.flush().unwrap();
+        client.write_all(raw_msg.as_slice())?;
+        client.flush()?;
 
         // read the response
         let len: u32;
@@ -82,17 +76,10 @@ impl ISOTcpClient {
         match client.read_exact(&mut out_buf[..]) {
             Ok(()) => {
                 println!("received response: with  {} bytes. \n {}\n", len, get_hexdump(&out_buf));
-                match self.spec.parse(&mut out_buf) {
-                    Ok(resp_iso_msg) => {
-                        Ok(resp_iso_msg)
-                    }
-                    Err(e) => {
-                        Err(IsoError { msg: e.msg })
-                    }
-                }
+                Ok(self.spec.parse(&out_buf)?)
             }
             Err(e) => {
-                Err(IsoError { msg: e.to_string() })
+                Err(e.into())
             }
         }
     }
@@ -0,0 +1,121 @@
+//! This module contains the default values and generated values (like the transmission date and time, STAN
+//! and RRN) of the fields of a message segment, that are filled in new messages and responses
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The highest STAN, the counter wraps around to 1 after it
+const MAX_STAN: u32 = 999_999;
+
+/// This enum represents a generator of the value of a field
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Generator {
+    /// The current (UTC) date and time as MMDDhhmmss (like DE7)
+    TransmissionTime,
+    /// The next value of the system trace audit number counter of the spec as 6 digits (like DE11)
+    Stan,
+    /// A retrieval reference number (like DE37) - the last digit of the year, the day of the year and the
+    /// hour (YDDDhh) followed by the STAN of the message
+    Rrn,
+}
+
+impl Generator {
+    /// Returns the value generated at time now for a message whose STAN is stan
+    pub(in crate::iso8583) fn generate(&self, now: &DateTime<Utc>, stan: &str) -> String {
+        match self {
+            Generator::TransmissionTime => now.format("%m%d%H%M%S").to_string(),
+            Generator::Stan => stan.to_string(),
+            Generator::Rrn => format!("{}{:03}{:02}{}", now.year() % 10, now.ordinal(), now.hour(), stan),
+        }
+    }
+}
+
+/// This struct represents how a field of a message segment is filled in a new message (or response)
+#[derive(Clone, Debug)]
+pub struct FieldDefault {
+    /// The name of the field
+    pub field: String,
+    /// The static value of the field
+    pub default: Option<String>,
+    /// The generator of the value of the field
+    pub generator: Option<Generator>,
+    /// Whether the value is copied from the request into a response (over the default or generated value)
+    pub echo: bool,
+}
+
+/// This struct represents the STAN counter of a spec
+#[derive(Debug, Default)]
+pub(in crate::iso8583) struct StanCounter(AtomicU32);
+
+impl StanCounter {
+    /// Returns the next STAN as 6 digits, from 000001 to 999999
+    pub(in crate::iso8583) fn next(&self) -> String {
+        let prev = self.0.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| Some(n % MAX_STAN + 1)).unwrap_or_default();
+        format!("{:06}", prev % MAX_STAN + 1)
+    }
+}
+
+/// Returns the message type of the response to a request of message type mti - the function (third) digit
+/// is incremented, for example 1100 to 1110 or 0800 to 0810. None is returned if mti is not a request
+pub fn response_mti(mti: &str) -> Option<String> {
+    let digits: Vec<u32> = mti.chars().map(|c| c.to_digit(10)).collect::<Option<Vec<u32>>>()?;
+    match digits.as_slice() {
+        [version, class, function, origin] if function % 2 == 0 => Some(format!("{}{}{}{}", version, class, function + 1, origin)),
+        _ => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::iso8583::builder::SpecBuilder;
+    use crate::iso8583::defaults::{Generator, response_mti, StanCounter};
+    use crate::iso8583::field::ContentType;
+    use crate::iso8583::field::Encoding::{ASCII, BINARY};
+    use crate::iso8583::iso_spec::new_msg;
+
+    #[test]
+    fn test_generators() {
+        let now = Utc.ymd(2024, 3, 5).and_hms(14, 7, 9);
+        assert_eq!(Generator::TransmissionTime.generate(&now, "000001"), "0305140709");
+        assert_eq!(Generator::Stan.generate(&now, "000042"), "000042");
+        assert_eq!(Generator::Rrn.generate(&now, "000042"), "406514000042");
+
+        let counter = StanCounter::default();
+        assert_eq!(counter.next(), "000001");
+        assert_eq!(counter.next(), "000002");
+        counter.0.store(999_999, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(counter.next(), "000001");
+
+        assert_eq!(response_mti("1100").as_deref(), Some("1110"));
+        assert_eq!(response_mti("0800").as_deref(), Some("0810"));
+        assert_eq!(response_mti("1110"), None);
+        assert_eq!(response_mti("11A0"), None);
+    }
+
+    #[test]
+    fn test_default_values() {
+        let builder = |default: &str| SpecBuilder::new("DefaultsSpec")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("0800", |m| m.selector(&["0800", "0810"])
+                .fixed("message_type", 4, ASCII).default_value("0800")
+                .bitmap("bitmap", BINARY, |b| b.fixed(11, "stan", 6, ASCII).generator(Generator::Stan)
+                    .fixed(70, "network_code", 3, ASCII).content(ContentType::Numeric, None, None).default_value(default)));
+
+        let spec = builder("301").build().unwrap();
+        let iso_msg = new_msg(&spec, spec.get_message_from_header("0800").unwrap());
+        assert_eq!(iso_msg.get("message_type").unwrap(), "0800");
+        assert_eq!(iso_msg.bmp_child_value(11).unwrap(), "000001");
+        assert_eq!(iso_msg.bmp_child_value(70).unwrap(), "301");
+        assert!(iso_msg.bmp.is_on(1));
+
+        match builder("ABC").build() {
+            Err(e) => assert_eq!(e.diagnostics[0].message, "default is not valid - value is not valid for content type Numeric"),
+            Ok(_) => panic!("expected a SpecError")
+        }
+    }
+}
@@ -1,13 +1,16 @@
 //! This module contains implementation of Variable and Fixed fields
 //!
 use crate::iso8583::iso_spec::IsoMsg;
+use crate::iso8583::trace::ParseTrace;
+use crate::iso8583::tlv::TlvField;
+use crate::iso8583::bitmap::BmpField;
+use crate::iso8583::rules::Violation;
 use std::fmt;
 use crate::iso8583::field::Encoding::{ASCII, EBCDIC, BCD, BINARY};
 use std::collections::HashMap;
-use std::io::{BufRead, Write};
+use std::io::Cursor;
 
 use serde::{Serialize, Deserialize};
-use byteorder::ByteOrder;
 
 
 /// This enum represents the encoding of a field (or length indicator for variable fields)
@@ -19,26 +22,282 @@ pub enum Encoding {
     BCD,
 }
 
-/// This struct represents a error in parsing a field/message
+/// This enum represents what the length indicator of a variable field counts. Digits and Chars both count the
+/// characters of the value i.e the digits (nibbles) of a BCD/BINARY field or the characters of a ASCII/EBCDIC field
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum LenUnit {
+    Bytes,
+    Digits,
+    Chars,
+}
+
+/// This enum represents the padding of a BCD field whose length is given in digits. A odd number of digits
+/// is padded with a nibble (0 or F) on the left (right justified) or on the right (left justified)
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum BcdPadding {
+    Left0,
+    LeftF,
+    Right0,
+    RightF,
+}
+
+impl BcdPadding {
+    /// Packs the digits (hex characters) into bytes padding a odd number of digits,
+    /// returns None if digits has a character that is not a hex digit
+    pub fn pack(&self, digits: &[u8]) -> Option<Vec<u8>> {
+        let mut padded = digits.to_vec();
+        if padded.len() % 2 == 1 {
+            match self {
+                BcdPadding::Left0 => padded.insert(0, b'0'),
+                BcdPadding::LeftF => padded.insert(0, b'F'),
+                BcdPadding::Right0 => padded.push(b'0'),
+                BcdPadding::RightF => padded.push(b'F'),
+            }
+        }
+        hex::decode(padded).ok()
+    }
+
+    /// Unpacks n digits (as upper case hex characters) from data, dropping the padding
+    pub fn unpack(&self, data: &[u8], n: usize) -> String {
+        let digits = hex::encode_upper(data);
+        let pad = digits.len().saturating_sub(n);
+        match self {
+            BcdPadding::Left0 | BcdPadding::LeftF => digits[pad..].to_string(),
+            BcdPadding::Right0 | BcdPadding::RightF => digits[..digits.len() - pad].to_string(),
+        }
+    }
+}
+
+/// This enum represents the ISO8583 content classes (n, a, an, ans, b and z) of a field
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum ContentType {
+    /// Digits only
+    #[serde(rename = "n")]
+    Numeric,
+    /// Letters and spaces
+    #[serde(rename = "a")]
+    Alpha,
+    /// Letters, digits and spaces
+    #[serde(rename = "an")]
+    AlphaNumeric,
+    /// Printable characters (letters, digits, spaces and special characters)
+    #[serde(rename = "ans")]
+    AlphaNumericSpecial,
+    /// Binary data (the value is a hex string)
+    #[serde(rename = "b")]
+    Binary,
+    /// Track 2/3 data - digits, separators (= or D) and sentinels (; and ?)
+    #[serde(rename = "z")]
+    Track,
+}
+
+impl ContentType {
+    /// Returns true if the value contains only characters allowed by the content type
+    pub fn allows(&self, value: &str) -> bool {
+        match self {
+            ContentType::Numeric => value.chars().all(|c| c.is_ascii_digit()),
+            ContentType::Alpha => value.chars().all(|c| c.is_ascii_alphabetic() || c == ' '),
+            ContentType::AlphaNumeric => value.chars().all(|c| c.is_ascii_alphanumeric() || c == ' '),
+            ContentType::AlphaNumericSpecial => value.chars().all(|c| (' '..='~').contains(&c)),
+            ContentType::Binary => value.len().is_multiple_of(2) && value.chars().all(|c| c.is_ascii_hexdigit()),
+            ContentType::Track => value.chars().all(|c| c.is_ascii_digit() || "=Dd;?".contains(c)),
+        }
+    }
+}
+
+/// This enum represents a violation of the content rules of a field
+#[derive(Debug, PartialEq)]
+pub enum ContentError {
+    /// The value has characters that are not allowed by the content type
+    InvalidCharacters(ContentType),
+    /// The value is shorter than the min_len of the field
+    TooShort { len: usize, min: u32 },
+    /// The value is longer than the max_len of the field
+    TooLong { len: usize, max: u32 },
+    /// The value of a BINARY (or BCD) field is not hex
+    InvalidHex,
+    /// The value of a Tlv (or AsciiTlv) field cannot be parsed into data elements, offset is where parsing failed
+    InvalidTlv { offset: usize },
+}
+
+impl fmt::Display for ContentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentError::InvalidCharacters(content_type) => write!(f, "value is not valid for content type {:?}", content_type),
+            ContentError::TooShort { len, min } => write!(f, "value is too short - {}, min: {}", len, min),
+            ContentError::TooLong { len, max } => write!(f, "value is too long - {}, max: {}", len, max),
+            ContentError::InvalidHex => write!(f, "value is not valid hex"),
+            ContentError::InvalidTlv { offset } => write!(f, "value is not valid tlv - at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for ContentError {}
+
+/// This struct represents the rules for the value of a field - the content type and the min and max length
+/// (in characters, or bytes for binary content). When lenient, a violation is only logged
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ContentRules {
+    pub content_type: Option<ContentType>,
+    pub min_len: Option<u32>,
+    pub max_len: Option<u32>,
+    pub lenient: bool,
+}
+
+impl ContentRules {
+    /// Returns a ContentError if the value breaks the rules (regardless of lenient)
+    pub fn verify(&self, value: &str) -> Result<(), ContentError> {
+        if let Some(content_type) = self.content_type {
+            if !content_type.allows(value) {
+                return Err(ContentError::InvalidCharacters(content_type));
+            }
+        }
+
+        let len = match self.content_type {
+            Some(ContentType::Binary) => value.len() / 2,
+            _ => value.chars().count()
+        };
+        match (self.min_len, self.max_len) {
+            (Some(min), _) if len < min as usize => Err(ContentError::TooShort { len, min }),
+            (_, Some(max)) if len > max as usize => Err(ContentError::TooLong { len, max }),
+            _ => Ok(())
+        }
+    }
+
+    /// Returns a ContentError if the value of field breaks the rules, unless the rules are lenient
+    pub fn check(&self, field: &str, value: &str) -> Result<(), ContentError> {
+        match self.verify(value) {
+            Err(e) if self.lenient => {
+                warn!("ignoring invalid value of {} - {}", field, e);
+                Ok(())
+            }
+            res => res
+        }
+    }
+}
+
+/// This enum represents the justification of a value that is shorter than a fixed field
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Justify {
+    /// The value is followed by the padding
+    Left,
+    /// The value is preceded by the padding
+    Right,
+}
+
+/// This enum represents what happens to a value that is longer than a fixed field
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Overflow {
+    /// The field fails to assemble
+    Error,
+    /// The value is cut to the len of the field
+    Truncate,
+}
+
+/// This struct represents how the value of a fixed field is fitted to its len on assemble. When not set, the pad_char
+/// and justification of numeric fields (with a numeric content type, or BCD digits) are '0' and Right (leading zeros)
+/// and those of other fields are ' ' and Left (trailing spaces). Values of BINARY (and BCD, unless it has a bcd_padding) fields are padded with zero bytes
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Padding {
+    pub pad_char: Option<char>,
+    pub justify: Option<Justify>,
+    pub overflow: Option<Overflow>,
+}
+
+/// Returns the number of bytes that hold n BCD digits
+pub(in crate::iso8583) fn bcd_len(n: usize) -> usize {
+    n.div_ceil(2)
+}
+
+/// This enum represents a error in parsing (or assembling) a field/message. Offsets are relative
+/// to the start of the message
 #[derive(Debug)]
-pub struct ParseError {
-    pub msg: String
+pub enum ParseError {
+    /// There is not enough data left to parse the field
+    InsufficientData { field: String, offset: usize, required: usize, available: usize },
+    /// The length indicator of a variable field could not be interpreted
+    BadLengthIndicator { field: String, offset: usize, len_ind: Vec<u8> },
+    /// A position is turned on in the bitmap but is not defined in the spec
+    UndefinedBitmapPosition { field: String, offset: usize, position: u32 },
+    /// The field data is not valid for the encoding of the field
+    InvalidEncoding { field: String, offset: usize },
+    /// No message segment is defined for the value of the header fields
+    NoMatchingSegment { header: String },
+    /// The field has no data (while assembling)
+    MissingFieldData { field: String },
+    /// There is data left after the last field of the message (when parsing strictly)
+    TrailingData { offset: usize, remaining: usize },
+    /// The value of the field breaks its content rules
+    InvalidContent { field: String, offset: usize, error: ContentError },
+    /// The value of the field is longer than what the field (or its length indicator) allows (while assembling)
+    FieldTooLong { field: String, len: usize, max: usize },
+    /// The message breaks the presence rules of its segment
+    RuleViolations(Vec<Violation>),
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "(iso8583:: parse-error: {})", self.msg)
+        match self {
+            ParseError::InsufficientData { field, offset, required, available } => {
+                write!(f, "(iso8583:: parse-error: not enough data to parse - {} at offset {}, required: {}, available: {})", field, offset, required, available)
+            }
+            ParseError::BadLengthIndicator { field, offset, len_ind } => {
+                write!(f, "(iso8583:: parse-error: bad length indicator for - {} at offset {}: {})", field, offset, hex::encode(len_ind))
+            }
+            ParseError::UndefinedBitmapPosition { field, offset, position } => {
+                write!(f, "(iso8583:: parse-error: position {} is on in {} at offset {}, but is not defined)", position, field, offset)
+            }
+            ParseError::InvalidEncoding { field, offset } => {
+                write!(f, "(iso8583:: parse-error: invalid data for the encoding of - {} at offset {})", field, offset)
+            }
+            ParseError::NoMatchingSegment { header } => {
+                write!(f, "(iso8583:: parse-error: message not found for header - {})", header)
+            }
+            ParseError::MissingFieldData { field } => {
+                write!(f, "(iso8583:: parse-error: field {} is not available!)", field)
+            }
+            ParseError::TrailingData { offset, remaining } => {
+                write!(f, "(iso8583:: parse-error: {} byte(s) of trailing data at offset {})", remaining, offset)
+            }
+            ParseError::InvalidContent { field, offset, error } => {
+                write!(f, "(iso8583:: parse-error: invalid content in - {} at offset {}: {})", field, offset, error)
+            }
+            ParseError::FieldTooLong { field, len, max } => {
+                write!(f, "(iso8583:: parse-error: {} is too long - {}, max: {})", field, len, max)
+            }
+            ParseError::RuleViolations(violations) => {
+                let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                write!(f, "(iso8583:: parse-error: message breaks the rules of its segment - {})", violations.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Reads n bytes of data for field from in_buf or returns a ParseError::InsufficientData if
+/// there isn't enough data left
+pub(in crate::iso8583) fn read_data(in_buf: &mut Cursor<&[u8]>, n: usize, field: &str) -> Result<Vec<u8>, ParseError> {
+    let offset = in_buf.position() as usize;
+    let available = in_buf.get_ref().len().saturating_sub(offset);
+
+    if available < n {
+        return Err(ParseError::InsufficientData { field: field.to_string(), offset, required: n, available });
     }
+
+    let data = in_buf.get_ref()[offset..offset + n].to_vec();
+    in_buf.set_position((offset + n) as u64);
+    Ok(data)
 }
 
 /// This trait represents a ISO field (specific implementations are FixedField, VarField and BmpField)
-pub trait Field: Sync {
+pub trait Field: Send + Sync {
     /// Returns the name of the field
     fn name(&self) -> &String;
 
-    /// Parses the field by reading from in_buf and stores the result into f2d_map
-    /// Returns a ParseError on failure
-    fn parse(&self, in_buf: &mut dyn BufRead, f2d_map: &mut HashMap<String, Vec<u8>>) -> Result<(), ParseError>;
+    /// Parses the field by reading from in_buf and stores the result into f2d_map, every parsed field
+    /// is recorded into trace. Returns a ParseError on failure
+    fn parse(&self, in_buf: &mut Cursor<&[u8]>, f2d_map: &mut HashMap<String, Vec<u8>>, trace: &mut ParseTrace) -> Result<(), ParseError>;
 
     /// Assembles the field i.e. appends it data into out_buf
     /// Returns the number of bytes written on success or a ParseError on failure
@@ -50,17 +309,32 @@ pub trait Field: Sync {
     /// Returns children as Vec
     fn children(&self) -> Vec<&dyn Field>;
 
-    /// Returns the child field by position
-    fn child_by_pos(&self, pos: u32) -> &dyn Field;
+    /// Returns the child field by position (if defined)
+    fn child_by_pos(&self, pos: u32) -> Option<&dyn Field>;
 
-    /// Returns child field by name
-    fn child_by_name(&self, name: &String) -> &dyn Field;
+    /// Returns child field by name (if defined)
+    fn child_by_name(&self, name: &String) -> Option<&dyn Field>;
 
     /// Returns a string that represents the field value in ascii
     fn to_string(&self, data: &Vec<u8>) -> String;
 
     /// Returns field value as binary (wire format)
     fn to_raw(&self, val: &str) -> Vec<u8>;
+
+    /// Returns a ContentError if val (as a string) breaks the content rules of the field
+    fn check(&self, _val: &str) -> Result<(), ContentError> {
+        Ok(())
+    }
+
+    /// Returns the field as a TlvField if its data is a list of TLV data elements
+    fn as_tlv(&self) -> Option<&TlvField> {
+        None
+    }
+
+    /// Returns the field as a BmpField if it is a bitmapped field
+    fn as_bmp(&self) -> Option<&BmpField> {
+        None
+    }
 }
 
 /// This struct represents a Fixed field
@@ -75,6 +349,53 @@ pub struct FixedField {
     pub encoding: Encoding,
     // Position of the field within the parent
     pub position: u32,
+    /// Subfields of the field (if any), in the order they appear in the field data
+    pub children: Vec<Box<dyn Field>>,
+    /// Padding of a BCD field, when set the len is in digits and the value of the field is held as digits
+    pub bcd_padding: Option<BcdPadding>,
+    /// How a value is padded (or truncated) to len on assemble
+    pub padding: Padding,
+    /// Rules for the content of the field
+    pub rules: ContentRules,
+}
+
+impl FixedField {
+    /// Returns the value padded or truncated to the len of the field, or a ParseError::FieldTooLong
+    /// if the value is too long (and the field doesn't truncate)
+    fn fit(&self, value: &[u8]) -> Result<Vec<u8>, ParseError> {
+        let len = self.len as usize;
+        if value.len() > len {
+            return match self.padding.overflow {
+                Some(Overflow::Truncate) => Ok(value[..len].to_vec()),
+                _ => Err(ParseError::FieldTooLong { field: self.name.clone(), len: value.len(), max: len })
+            };
+        }
+
+        // the padding depends only on the field (and never on the value) - fields with numeric content and BCD
+        // digits are numeric, as are BINARY/BCD values that are padded with zero bytes
+        let numeric = match (self.rules.content_type, self.encoding, self.bcd_padding) {
+            (Some(content_type), _, _) => content_type == ContentType::Numeric,
+            (None, Encoding::ASCII, _) | (None, Encoding::EBCDIC, _) => false,
+            _ => true
+        };
+
+        let pad = match (self.encoding, self.bcd_padding) {
+            (Encoding::BINARY, _) | (Encoding::BCD, None) => 0,
+            _ => {
+                let pad_char = self.padding.pad_char.unwrap_or(if numeric { '0' } else { ' ' }) as u8;
+                match self.encoding {
+                    Encoding::EBCDIC => encoding8::ascii::to_ebcdic(pad_char),
+                    _ => pad_char
+                }
+            }
+        };
+
+        let padding = vec![pad; len - value.len()];
+        match self.padding.justify.unwrap_or(if numeric { Justify::Right } else { Justify::Left }) {
+            Justify::Left => Ok([value, &padding].concat()),
+            Justify::Right => Ok([&padding, value].concat())
+        }
+    }
 }
 
 impl Field for FixedField {
@@ -82,27 +403,36 @@ impl Field for FixedField {
         &self.name
     }
 
-    fn parse(self: &Self, in_buf: &mut dyn BufRead, f2d_map: &mut HashMap<String, Vec<u8>>) -> Result<(), ParseError> {
-        let mut f_data = vec![0; self.len as usize];
-        match in_buf.read_exact(&mut f_data[..]) {
-            Ok(_) => {
-                f2d_map.insert(self.name.clone(), f_data);
-                Ok(())
+    fn parse(&self, in_buf: &mut Cursor<&[u8]>, f2d_map: &mut HashMap<String, Vec<u8>>, trace: &mut ParseTrace) -> Result<(), ParseError> {
+        let offset = in_buf.position() as usize;
+        let value = match &self.bcd_padding {
+            Some(padding) => {
+                let f_data = read_data(in_buf, bcd_len(self.len as usize), &self.name)?;
+                let digits = padding.unpack(&f_data, self.len as usize);
+                trace.record_value(self, offset, &[], &f_data, digits.clone());
+                digits.into_bytes()
             }
-            Err(_) => {
-                Err(ParseError { msg: format!("not enough data to parse - {}", self.name) })
+            None => {
+                let f_data = read_data(in_buf, self.len as usize, &self.name)?;
+                trace.record(self, offset, &[], &f_data);
+                f_data
             }
-        }
+        };
+        self.rules.check(&self.name, &self.to_string(&value))
+            .map_err(|error| ParseError::InvalidContent { field: self.name.clone(), offset, error })?;
+        f2d_map.insert(self.name.clone(), value);
+        parse_children(&self.children(), in_buf, offset, f2d_map, trace)
     }
 
-    fn assemble(self: &Self, out_buf: &mut Vec<u8>, iso_msg: &IsoMsg) -> Result<u32, ParseError> {
+    fn assemble(&self, out_buf: &mut Vec<u8>, iso_msg: &IsoMsg) -> Result<u32, ParseError> {
         match iso_msg.fd_map.get(&self.name) {
             Some(fd) => {
-                out_buf.extend(fd);
-                Ok(fd.as_slice().len() as u32)
+                let data = pack_bcd(&self.bcd_padding, &self.fit(fd)?, &self.name, out_buf.len())?;
+                out_buf.extend(&data);
+                Ok(data.len() as u32)
             }
             None => {
-                Err(ParseError { msg: format!("field {} is not available!", self.name) })
+                Err(ParseError::MissingFieldData { field: self.name.clone() })
             }
         }
     }
@@ -112,24 +442,34 @@ impl Field for FixedField {
     }
 
     fn children(&self) -> Vec<&dyn Field> {
-        //unimplemented!("nested fields not supported for {}", self.name)
-        vec![]
+        self.children.iter().map(|f| f.as_ref()).collect()
     }
 
-    fn child_by_pos(&self, _pos: u32) -> &dyn Field {
-        unimplemented!()
+    fn child_by_pos(&self, pos: u32) -> Option<&dyn Field> {
+        self.children.iter().find(|f| f.position() == pos).map(|f| f.as_ref())
     }
 
-    fn child_by_name(&self, _name: &String) -> &dyn Field {
-        unimplemented!()
+    fn child_by_name(&self, name: &String) -> Option<&dyn Field> {
+        self.children.iter().find(|f| f.name() == name).map(|f| f.as_ref())
     }
 
     fn to_string(&self, data: &Vec<u8>) -> String {
-        vec_to_string(&self.encoding, data)
+        match self.bcd_padding {
+            Some(_) => String::from_utf8_lossy(data).to_string(),
+            None => vec_to_string(&self.encoding, data)
+        }
     }
 
     fn to_raw(&self, val: &str) -> Vec<u8> {
-        string_to_vec(&self.encoding, val)
+        match self.bcd_padding {
+            Some(_) => val.to_uppercase().into_bytes(),
+            None => string_to_vec(&self.encoding, val)
+        }
+    }
+
+    fn check(&self, val: &str) -> Result<(), ContentError> {
+        check_hex(&self.encoding, &self.bcd_padding, val)?;
+        self.rules.check(&self.name, val)
     }
 }
 
@@ -146,78 +486,73 @@ pub struct VarField {
     pub encoding: Encoding,
     // Position of field within parent
     pub position: u32,
+    /// Subfields of the field (if any), in the order they appear in the field data
+    pub children: Vec<Box<dyn Field>>,
+    /// Padding of a BCD field, when set the value of the field is held as digits
+    pub bcd_padding: Option<BcdPadding>,
+    /// What the length indicator counts, defaults to Digits for a BCD field with a bcd_padding and Bytes otherwise
+    pub len_unit: Option<LenUnit>,
+    /// Rules for the content of the field
+    pub rules: ContentRules,
 }
 
 
 impl VarField {
-    /// Returns the length of data in the variable field
-    fn data_len(&self, data: &Vec<u8>) -> usize
+    /// Returns the value of the length indicator (read at offset) or a ParseError::BadLengthIndicator
+    /// if it cannot be interpreted
+    fn data_len(&self, data: &Vec<u8>, offset: usize) -> Result<usize, ParseError>
     {
-        match self.len_encoding {
-            Encoding::ASCII => {
-                String::from_utf8(data.clone()).expect("").parse::<usize>().unwrap()
-            }
-            Encoding::EBCDIC => {
-                ebcdic_to_ascii(data).parse::<usize>().unwrap()
-            }
-            Encoding::BINARY => {
-                match data.len() {
-                    1 => data[0] as usize,
-                    2 => byteorder::BigEndian::read_u16(&data[..]) as usize,
-                    _ => panic!("Cannot support more than 2 bytes of length indicator when expressed in binary")
-                }
-            }
-            Encoding::BCD => {
-                match data.len() {
-                    1 => hex::encode(data).parse::<usize>().unwrap(),
-                    2 => hex::encode(data).parse::<usize>().unwrap(),
-                    _ => panic!("Cannot support more than 2 bytes (4 BCD digits) of length indicator when expressed in bcd")
-                }
-            }
+        decode_len(&self.len_encoding, data)
+            .ok_or_else(|| ParseError::BadLengthIndicator { field: self.name.clone(), offset, len_ind: data.clone() })
+    }
+
+    /// Builds and returns the length indicator based on encoding of the field as a Vec<u8> or a
+    /// ParseError::FieldTooLong if len does not fit in the length indicator
+    fn build_len_ind(&self, len: usize) -> Result<Vec<u8>, ParseError> {
+        encode_len(&self.len_encoding, self.len, len)
+            .ok_or_else(|| ParseError::FieldTooLong { field: self.name.clone(), len, max: max_len(&self.len_encoding, self.len) })
+    }
+
+    fn len_unit(&self) -> LenUnit {
+        self.len_unit.unwrap_or(if self.bcd_padding.is_some() { LenUnit::Digits } else { LenUnit::Bytes })
+    }
+
+    /// Returns true if each byte of the field data holds 2 digits
+    fn is_packed(&self) -> bool {
+        matches!(self.encoding, Encoding::BCD | Encoding::BINARY)
+    }
+
+    /// Returns the number of bytes of data for the value of the length indicator
+    fn wire_len(&self, len: usize) -> usize {
+        match self.len_unit() {
+            LenUnit::Digits | LenUnit::Chars if self.is_packed() => bcd_len(len),
+            _ => len
         }
     }
 
-    /// Builds and returns the length indicator based on encoding of the field as a Vec<u8>
-    fn build_len_ind(&self, len: usize) -> Vec<u8> {
-        match self.len_encoding {
-            Encoding::ASCII => {
-                match self.len {
-                    1 => format!("{:01}", len).into_bytes(),
-                    2 => format!("{:02}", len).into_bytes(),
-                    3 => format!("{:03}", len).into_bytes(),
-                    _ => unimplemented!("len-ind cannot exceed 3")
-                }
-            }
-            Encoding::EBCDIC => {
-                match self.len {
-                    1 => ascii_to_ebcdic(&mut format!("{:01}", len).into_bytes()),
-                    2 => ascii_to_ebcdic(&mut format!("{:02}", len).into_bytes()),
-                    3 => ascii_to_ebcdic(&mut format!("{:03}", len).into_bytes()),
-                    _ => unimplemented!("len-ind cannot exceed 3")
+    /// Returns the number of digits held in the field data given the value of the length indicator
+    fn digits(&self, data: &[u8], len: usize, padding: &BcdPadding) -> usize {
+        match self.len_unit() {
+            LenUnit::Bytes => {
+                // a F padding nibble (unlike a 0) can be told apart from the digits
+                let hex = hex::encode_upper(data);
+                match padding {
+                    BcdPadding::LeftF if hex.starts_with('F') => hex.len() - 1,
+                    BcdPadding::RightF if hex.ends_with('F') => hex.len() - 1,
+                    _ => hex.len()
                 }
             }
+            _ => len
+        }
+    }
 
-            Encoding::BINARY => {
-                let mut len_ind = Vec::<u8>::new();
-                match self.len {
-                    1 => {
-                        len_ind.write(&vec![len as u8]).unwrap();
-                        len_ind
-                    }
-                    2 => {
-                        byteorder::BigEndian::write_u16(&mut len_ind, len as u16);
-                        len_ind
-                    }
-                    _ => panic!("Cannot support more than 2 bytes of length indicator when expressed in binary")
-                }
-            }
-            Encoding::BCD => {
-                match self.len {
-                    1 => hex::decode(format!("{:02}", len)).unwrap(),
-                    2 => hex::decode(format!("{:04}", len)).unwrap(),
-                    _ => panic!("Cannot support more than 2 bytes (4 BCD digits) of length indicator when expressed in bcd")
-                }
-            }
+    /// Returns the value of the length indicator given the value of the field and its wire format
+    fn len_of(&self, value: &[u8], data: &[u8]) -> usize {
+        match self.len_unit() {
+            LenUnit::Bytes => data.len(),
+            _ if self.bcd_padding.is_some() => value.len(),
+            _ if self.is_packed() => data.len() * 2,
+            _ => data.len()
         }
     }
 }
@@ -228,44 +563,42 @@ impl Field for VarField
         &self.name
     }
 
-    fn parse(&self, in_buf: &mut dyn BufRead, f2d_map: &mut HashMap<String, Vec<u8>>) -> Result<(), ParseError> {
-        let mut len_data = vec![0; self.len as usize];
-        match in_buf.read_exact(&mut len_data[..]) {
-            Ok(_) => {
-                trace!("parsed-data (len-ind) : {}", hex::encode(&len_data));
-
-
-                let data_len = self.data_len(&len_data);
-                let mut f_data = vec![0; data_len as usize];
-
-                match in_buf.read_exact(&mut f_data[..]) {
-                    Ok(_) => {
-                        f2d_map.insert(self.name.clone(), f_data);
-                        Ok(())
-                    }
-                    Err(e) => {
-                        Result::Err(ParseError { msg: format!("insufficient data, failed to parse {}, Error = {}", self.name, e.to_string()) })
-                    }
-                }
+    fn parse(&self, in_buf: &mut Cursor<&[u8]>, f2d_map: &mut HashMap<String, Vec<u8>>, trace: &mut ParseTrace) -> Result<(), ParseError> {
+        let offset = in_buf.position() as usize;
+        let len_data = read_data(in_buf, self.len as usize, &self.name)?;
+        trace!("parsed-data (len-ind) : {}", hex::encode(&len_data));
+
+        let data_len = self.data_len(&len_data, offset)?;
+        let f_data = read_data(in_buf, self.wire_len(data_len), &self.name)?;
+        let value = match &self.bcd_padding {
+            Some(padding) => {
+                let digits = padding.unpack(&f_data, self.digits(&f_data, data_len, padding));
+                trace.record_value(self, offset, &len_data, &f_data, digits.clone());
+                digits.into_bytes()
             }
-            Err(_) => {
-                Result::Err(ParseError { msg: format!("insufficient data, failed to parse length indicator for -  {}", self.name) })
+            None => {
+                trace.record(self, offset, &len_data, &f_data);
+                f_data
             }
-        }
+        };
+        self.rules.check(&self.name, &self.to_string(&value))
+            .map_err(|error| ParseError::InvalidContent { field: self.name.clone(), offset, error })?;
+        f2d_map.insert(self.name.clone(), value);
+        parse_children(&self.children(), in_buf, offset + len_data.len(), f2d_map, trace)
     }
 
 
     fn assemble(&self, out_buf: &mut Vec<u8>, iso_msg: &IsoMsg) -> Result<u32, ParseError> {
         match iso_msg.fd_map.get(&self.name) {
             Some(fd) => {
-                let len_ind = self.build_len_ind(fd.len());
+                let data = pack_bcd(&self.bcd_padding, fd, &self.name, out_buf.len())?;
+                let len_ind = self.build_len_ind(self.len_of(fd, &data))?;
                 out_buf.extend(len_ind);
-                out_buf.extend(fd);
-                //fd.as_slice().iter().for_each(|d| out_buf.push(*d));
-                Ok(fd.as_slice().len() as u32)
+                out_buf.extend(&data);
+                Ok(data.len() as u32)
             }
             None => {
-                Err(ParseError { msg: format!("field {} is not available!", self.name) })
+                Err(ParseError::MissingFieldData { field: self.name.clone() })
             }
         }
     }
@@ -277,32 +610,69 @@ impl Field for VarField
 
 
     fn children(&self) -> Vec<&dyn Field> {
-        //unimplemented!("nested fields not supported for {}", self.name)
-        vec![]
+        self.children.iter().map(|f| f.as_ref()).collect()
     }
 
-
-    fn child_by_pos(&self, _pos: u32) -> &dyn Field {
-        unimplemented!()
+    fn child_by_pos(&self, pos: u32) -> Option<&dyn Field> {
+        self.children.iter().find(|f| f.position() == pos).map(|f| f.as_ref())
     }
 
-    fn child_by_name(&self, _name: &String) -> &dyn Field {
-        unimplemented!()
+    fn child_by_name(&self, name: &String) -> Option<&dyn Field> {
+        self.children.iter().find(|f| f.name() == name).map(|f| f.as_ref())
     }
 
     fn to_string(&self, data: &Vec<u8>) -> String {
-        vec_to_string(&self.encoding, data)
+        match self.bcd_padding {
+            Some(_) => String::from_utf8_lossy(data).to_string(),
+            None => vec_to_string(&self.encoding, data)
+        }
     }
 
     fn to_raw(&self, val: &str) -> Vec<u8> {
-        string_to_vec(&self.encoding, val)
+        match self.bcd_padding {
+            Some(_) => val.to_uppercase().into_bytes(),
+            None => string_to_vec(&self.encoding, val)
+        }
+    }
+
+    fn check(&self, val: &str) -> Result<(), ContentError> {
+        check_hex(&self.encoding, &self.bcd_padding, val)?;
+        self.rules.check(&self.name, val)
     }
 }
 
+/// Returns the wire format of the value of a field, which is the value itself unless the field is BCD with
+/// a length in digits (in which case the digits are packed)
+fn pack_bcd(bcd_padding: &Option<BcdPadding>, value: &[u8], field: &str, offset: usize) -> Result<Vec<u8>, ParseError> {
+    match bcd_padding {
+        Some(padding) => padding.pack(value).ok_or_else(|| ParseError::InvalidEncoding { field: field.to_string(), offset }),
+        None => Ok(value.to_vec())
+    }
+}
+
+/// Parses the children (if any) of a field whose data was just read from in_buf starting at data_offset. The
+/// children are read from the field data only, data not consumed by the children is ignored
+pub(in crate::iso8583) fn parse_children(children: &[&dyn Field], in_buf: &Cursor<&[u8]>, data_offset: usize,
+                                         f2d_map: &mut HashMap<String, Vec<u8>>, trace: &mut ParseTrace) -> Result<(), ParseError> {
+    if children.is_empty() {
+        return Ok(());
+    }
+
+    let end = in_buf.position() as usize;
+    let mut c_buf = Cursor::new(&in_buf.get_ref()[..end]);
+    c_buf.set_position(data_offset as u64);
+
+    for f in children {
+        debug!("parsing sub-field - {}", f.name());
+        f.parse(&mut c_buf, f2d_map, trace)?;
+    }
+    Ok(())
+}
+
 pub(in crate::iso8583) fn vec_to_string(encoding: &Encoding, data: &Vec<u8>) -> String {
     match encoding {
         ASCII => {
-            String::from_utf8(data.clone()).unwrap()
+            String::from_utf8_lossy(data).to_string()
         }
         EBCDIC => {
             ebcdic_to_ascii(data)
@@ -316,21 +686,89 @@ pub(in crate::iso8583) fn vec_to_string(encoding: &Encoding, data: &Vec<u8>) ->
     }
 }
 
+/// Returns the length encoded in data (a length indicator) as per the encoding or None if it cannot be interpreted
+pub(in crate::iso8583) fn decode_len(encoding: &Encoding, data: &[u8]) -> Option<usize> {
+    match encoding {
+        Encoding::ASCII => {
+            std::str::from_utf8(data).ok().and_then(parse_digits)
+        }
+        Encoding::EBCDIC => {
+            parse_digits(ebcdic_to_ascii(&data.to_vec()).as_str())
+        }
+        Encoding::BINARY => {
+            match data.len() {
+                1..=4 => Some(data.iter().fold(0usize, |l, b| l << 8 | *b as usize)),
+                _ => None
+            }
+        }
+        Encoding::BCD => {
+            match data.len() {
+                1..=3 => parse_digits(hex::encode(data).as_str()),
+                _ => None
+            }
+        }
+    }
+}
+
+/// Returns a length indicator of len_ind bytes (or characters) that holds len as per the encoding,
+/// or None if len does not fit
+pub(in crate::iso8583) fn encode_len(encoding: &Encoding, len_ind: u32, len: usize) -> Option<Vec<u8>> {
+    if len > max_len(encoding, len_ind) {
+        return None;
+    }
+
+    let len_ind = len_ind as usize;
+    match encoding {
+        Encoding::ASCII => Some(format!("{:0width$}", len, width = len_ind).into_bytes()),
+        Encoding::EBCDIC => Some(ascii_to_ebcdic(&mut format!("{:0width$}", len, width = len_ind).into_bytes())),
+        Encoding::BINARY => Some((len as u64).to_be_bytes()[8usize.saturating_sub(len_ind)..].to_vec()),
+        Encoding::BCD => hex::decode(format!("{:0width$}", len, width = len_ind * 2)).ok()
+    }
+}
+
+/// Returns the largest length that a length indicator of len_ind bytes (or characters) can hold
+pub(in crate::iso8583) fn max_len(encoding: &Encoding, len_ind: u32) -> usize {
+    let max = match encoding {
+        Encoding::ASCII | Encoding::EBCDIC => 10u64.saturating_pow(len_ind) - 1,
+        Encoding::BINARY => (1u64 << (8 * len_ind.min(7))) - 1,
+        Encoding::BCD => 10u64.saturating_pow(len_ind * 2) - 1
+    };
+    max.min(usize::MAX as u64) as usize
+}
+
+/// Parses a string of decimal digits (only) into a usize
+fn parse_digits(s: &str) -> Option<usize> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse::<usize>().ok()
+}
+
 /// Converts EBCDIC bytes into a ASCII string
-fn ebcdic_to_ascii(data: &Vec<u8>) -> String {
+pub(in crate::iso8583) fn ebcdic_to_ascii(data: &Vec<u8>) -> String {
     let mut ascii_str = String::new();
     data.iter().for_each(|f| ascii_str.push(char::from(encoding8::ebcdic::to_ascii(f.clone()))));
     ascii_str
 }
 
 /// Converts ASCII bytes to EBCDIC bytes
-fn ascii_to_ebcdic(data: &mut Vec<u8>) -> Vec<u8> {
+pub(in crate::iso8583) fn ascii_to_ebcdic(data: &mut Vec<u8>) -> Vec<u8> {
     for i in 0..data.len() {
         encoding8::ascii::make_ebcdic(data.get_mut(i).unwrap())
     }
     data.to_vec()
 }
 
+/// Returns a ContentError::InvalidHex (regardless of lenient) if value cannot be converted to the wire format - the
+/// value of a BINARY field is a even number of hex digits and that of a BCD field (without a bcd_padding) hex digits
+pub(in crate::iso8583) fn check_hex(encoding: &Encoding, bcd_padding: &Option<BcdPadding>, value: &str) -> Result<(), ContentError> {
+    let is_hex = value.chars().all(|c| c.is_ascii_hexdigit());
+    match (encoding, bcd_padding) {
+        (BINARY, _) if !is_hex || !value.len().is_multiple_of(2) => Err(ContentError::InvalidHex),
+        (BCD, None) if !is_hex => Err(ContentError::InvalidHex),
+        _ => Ok(())
+    }
+}
 
 pub(in crate::iso8583) fn string_to_vec(encoding: &Encoding, data: &str) -> Vec<u8> {
     match encoding {
@@ -346,12 +784,205 @@ pub(in crate::iso8583) fn string_to_vec(encoding: &Encoding, data: &str) -> Vec<
             hex::decode(data).unwrap()
         }
         BCD => {
-            hex::decode(data).unwrap()
+            // a odd number of digits is right justified
+            if data.len() % 2 == 1 {
+                hex::decode(format!("0{}", data)).unwrap()
+            } else {
+                hex::decode(data).unwrap()
+            }
         }
     }
 }
 
 
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::field::{BcdPadding, ContentError, ContentRules, ContentType, decode_len, encode_len, Encoding, FixedField, Justify, Overflow, Padding, ParseError, string_to_vec};
+    use crate::iso8583::field::Encoding::{ASCII, BCD, BINARY, EBCDIC};
+    use crate::iso8583::iso_spec::{new_msg, Spec};
+    use crate::iso8583::IsoError;
+
+    #[test]
+    fn test_bcd_padding() {
+        assert_eq!(BcdPadding::Left0.pack(b"123").unwrap(), vec![0x01, 0x23]);
+        assert_eq!(BcdPadding::LeftF.pack(b"123").unwrap(), vec![0xF1, 0x23]);
+        assert_eq!(BcdPadding::Right0.pack(b"123").unwrap(), vec![0x12, 0x30]);
+        assert_eq!(BcdPadding::RightF.pack(b"1234").unwrap(), vec![0x12, 0x34]);
+        assert!(BcdPadding::RightF.pack(b"12X").is_none());
+
+        assert_eq!(BcdPadding::Left0.unpack(&[0x01, 0x23], 3), "123");
+        assert_eq!(BcdPadding::LeftF.unpack(&[0xF1, 0x23], 3), "123");
+        assert_eq!(BcdPadding::RightF.unpack(&[0x12, 0x3F], 3), "123");
+        assert_eq!(BcdPadding::Right0.unpack(&[0x12, 0x34], 4), "1234");
 
+        assert_eq!(string_to_vec(&BCD, "123"), vec![0x01, 0x23]);
+    }
+
+    #[test]
+    fn test_len_ind() {
+        assert_eq!(encode_len(&ASCII, 4, 123).unwrap(), b"0123".to_vec());
+        assert_eq!(encode_len(&EBCDIC, 6, 123).unwrap(), vec![0xF0, 0xF0, 0xF0, 0xF1, 0xF2, 0xF3]);
+        assert_eq!(encode_len(&BINARY, 2, 300).unwrap(), vec![0x01, 0x2C]);
+        assert_eq!(encode_len(&BINARY, 3, 300).unwrap(), vec![0x00, 0x01, 0x2C]);
+        assert_eq!(encode_len(&BCD, 3, 12345).unwrap(), vec![0x01, 0x23, 0x45]);
+        assert!(encode_len(&ASCII, 2, 100).is_none());
+        assert!(encode_len(&BINARY, 1, 256).is_none());
+        assert!(encode_len(&BCD, 1, 100).is_none());
+
+        assert_eq!(decode_len(&ASCII, b"0123"), Some(123));
+        assert_eq!(decode_len(&BINARY, &[0x00, 0x01, 0x2C]), Some(300));
+        assert_eq!(decode_len(&BCD, &[0x01, 0x23, 0x45]), Some(12345));
+        assert_eq!(decode_len(&BCD, &[0x1A]), None);
+    }
+
+    #[test]
+    fn test_len_units() {
+        let spec = Spec::from_yaml_str(r#"name: LenSpec
+id: 1
+header_fields:
+  - name: "message_type"
+    id: 1
+    type: Fixed
+    len: 4
+    data_encoding: ASCII
+messages:
+  - name: "0100"
+    selector: ["0100"]
+    id: 1
+    fields:
+      - name: "message_type"
+        id: 1
+        type: Fixed
+        len: 4
+        data_encoding: ASCII
+      - name: "bitmap"
+        id: 2
+        type: Bitmapped
+        len: 0
+        data_encoding: BINARY
+        children:
+          - name: "pan"
+            id: 2
+            type: Variable
+            len: 1
+            len_encoding: BINARY
+            data_encoding: BCD
+            position: 2
+            len_unit: Digits
+          - name: "track_2"
+            id: 35
+            type: Variable
+            len: 1
+            len_encoding: BCD
+            data_encoding: BCD
+            position: 35
+            bcd_padding: RightF
+            len_unit: Bytes
+          - name: "private_1"
+            id: 61
+            type: Variable
+            len: 4
+            len_encoding: ASCII
+            data_encoding: ASCII
+            position: 61
+          - name: "private_2"
+            id: 62
+            type: Variable
+            len: 6
+            len_encoding: EBCDIC
+            data_encoding: EBCDIC
+            position: 62
+          - name: "private_3"
+            id: 63
+            type: Variable
+            len: 2
+            len_encoding: BINARY
+            data_encoding: BINARY
+            position: 63
+"#).unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("0100").unwrap());
+        iso_msg.set("message_type", "0100").unwrap();
+        iso_msg.set_on(2, "4761739001010119").unwrap();
+        iso_msg.set_on(35, "476173900101011D2512").unwrap();
+        iso_msg.set_on(61, "A".repeat(1200).as_str()).unwrap();
+        iso_msg.set_on(62, "private").unwrap();
+        iso_msg.set_on(63, "ab".repeat(300).as_str()).unwrap();
+
+        let data = iso_msg.assemble().unwrap();
+        let report = spec.parse_traced(&data);
+        assert!(report.is_ok());
+        let len_inds: Vec<String> = report.fields.iter().filter(|f| f.position > 0).map(|f| hex::encode(&f.len_ind)).collect();
+        assert_eq!(len_inds, vec!["10", "10", "31323030", "f0f0f0f0f0f7", "012c"]);
+
+        let parsed = report.msg.unwrap();
+        assert_eq!(parsed.bmp_child_value(2).unwrap(), "4761739001010119");
+        assert_eq!(parsed.bmp_child_value(35).unwrap(), "476173900101011D2512");
+        assert_eq!(parsed.bmp_child_value(61).unwrap().len(), 1200);
+        assert_eq!(parsed.bmp_child_value(63).unwrap().len(), 600);
+
+        // the value does not fit in the length indicator
+        iso_msg.set_on(2, "47".repeat(128).as_str()).unwrap();
+        match iso_msg.assemble() {
+            Err(IsoError::Assemble(ParseError::FieldTooLong { field, len, max })) => {
+                assert_eq!((field.as_str(), len, max), ("pan", 256, 255));
+            }
+            _ => panic!("expected a FieldTooLong error")
+        }
+    }
 
+    #[test]
+    fn test_content_rules() {
+        assert!(ContentType::Numeric.allows("0123456789"));
+        assert!(!ContentType::Numeric.allows("12 3"));
+        assert!(ContentType::Alpha.allows("APPROVED OK"));
+        assert!(!ContentType::Alpha.allows("APPR01"));
+        assert!(ContentType::AlphaNumeric.allows("APPR01"));
+        assert!(!ContentType::AlphaNumeric.allows("APPR-01"));
+        assert!(ContentType::AlphaNumericSpecial.allows("APPR-01 #2"));
+        assert!(!ContentType::AlphaNumericSpecial.allows("APPR\n"));
+        assert!(ContentType::Binary.allows("0aFF"));
+        assert!(!ContentType::Binary.allows("0aF"));
+        assert!(ContentType::Track.allows(";4567909845671235=2512101?"));
+        assert!(!ContentType::Track.allows("4567^2512"));
+
+        let rules = ContentRules { content_type: Some(ContentType::Binary), min_len: Some(2), max_len: Some(4), lenient: false };
+        assert_eq!(rules.verify("00FF"), Ok(()));
+        assert_eq!(rules.verify("00"), Err(ContentError::TooShort { len: 1, min: 2 }));
+        assert_eq!(rules.verify("0011223344"), Err(ContentError::TooLong { len: 5, max: 4 }));
+        assert_eq!(rules.check("f", "XX"), Err(ContentError::InvalidCharacters(ContentType::Binary)));
+        assert_eq!(ContentRules { lenient: true, ..rules }.check("f", "XX"), Ok(()));
+    }
 
+    #[test]
+    fn test_fixed_padding() {
+        let field = |encoding: Encoding, padding: Padding| FixedField {
+            name: "f".to_string(),
+            id: 1,
+            len: 6,
+            encoding,
+            position: 1,
+            children: vec![],
+            bcd_padding: None,
+            padding,
+            rules: ContentRules::default(),
+        };
+
+        // the padding of a field without a content type does not depend on the value
+        let default = field(ASCII, Padding::default());
+        assert_eq!(default.fit(b"29").unwrap(), b"29    ".to_vec());
+        assert_eq!(default.fit(b"AB").unwrap(), b"AB    ".to_vec());
+        assert_eq!(default.fit(b"123456").unwrap(), b"123456".to_vec());
+        assert!(matches!(default.fit(b"1234567"), Err(ParseError::FieldTooLong { len: 7, max: 6, .. })));
+
+        let custom = field(ASCII, Padding { pad_char: Some('*'), justify: Some(Justify::Right), overflow: Some(Overflow::Truncate) });
+        assert_eq!(custom.fit(b"AB").unwrap(), b"****AB".to_vec());
+        assert_eq!(custom.fit(b"ABCDEFGH").unwrap(), b"ABCDEF".to_vec());
+
+        assert_eq!(field(EBCDIC, Padding::default()).fit(&[0xC1]).unwrap(), vec![0xC1, 0x40, 0x40, 0x40, 0x40, 0x40]);
+        assert_eq!(field(BINARY, Padding::default()).fit(&[0x01]).unwrap(), vec![0, 0, 0, 0, 0, 0x01]);
+
+        let numeric = FixedField { rules: ContentRules { content_type: Some(ContentType::Numeric), ..ContentRules::default() }, ..field(ASCII, Padding::default()) };
+        assert_eq!(numeric.fit(b"29").unwrap(), b"000029".to_vec());
+    }
+}
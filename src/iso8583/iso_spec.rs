@@ -2,59 +2,133 @@
 //!
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
-use std::io::Cursor;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
 
-use crate::iso8583::{bitmap, IsoError};
-use crate::iso8583::field::{Field, ParseError};
-use crate::iso8583::yaml_de::YMessageSegment;
+use chrono::Utc;
+
+use crate::iso8583::{bitmap, yaml_de, IsoError};
+use crate::iso8583::field::{Field, ParseError, parse_children};
+use crate::iso8583::tlv::{find_tag, Tlv, TlvField};
+use crate::iso8583::rules::{check_rules, FieldRule, Violation};
+use crate::iso8583::defaults::{FieldDefault, Generator, response_mti, StanCounter};
+use crate::iso8583::selector::Selector;
+use crate::iso8583::yaml_de::{YMessageSegment, YSpec};
 use crate::iso8583::bitmap::Bitmap;
 use crate::iso8583::config::Config;
+use crate::iso8583::registry::default_registry;
+use crate::iso8583::trace::{ParseReport, ParseTrace};
 use crate::crypto::pin::generate_pin_block;
-use crate::crypto::mac::generate_mac;
+use crate::crypto::mac::{generate_mac, MacError};
+use crate::crypto::decode_key;
 
-// Reads the spec definitions from YAML file
-lazy_static! {
-static ref ALL_SPECS: std::collections::HashMap<String,Spec> ={
+/// This struct is the definition of the specification - layout of fields etc..
+pub struct Spec {
+    pub(in crate::iso8583) name: String,
+    pub(in crate::iso8583) id: u32,
+    pub(in crate::iso8583) messages: Vec<MessageSegment>,
+    pub(in crate::iso8583) header_fields: Vec<Box<dyn Field>>,
+    /// The counter of the STANs generated for messages of the spec
+    pub(in crate::iso8583) stan: StanCounter,
+    /// The (resolved) definition the spec was created from, used to export the spec
+    pub(in crate::iso8583) definition: YSpec,
+}
 
-        println!("current-dir: {}",std::env::current_dir().unwrap().to_str().unwrap());
-        let mut spec_file = String::new();
+/// This enum represents the formats a spec can be defined in
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SpecFormat {
+    Yaml,
+    Json,
+    Toml,
+}
 
-        match std::env::var_os("SPEC_FILE") {
-            Some(v) => {
-               spec_file.push_str(v.to_str().unwrap());
-               println!("spec-file: {}",spec_file)
-            }
+impl SpecFormat {
+    /// Returns the format of a spec file given its extension (yaml or yml, json and toml) or None for
+    /// any other extension
+    pub fn from_path(path: &Path) -> Option<SpecFormat> {
+        match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()).as_deref() {
+            Some("yaml") | Some("yml") => Some(SpecFormat::Yaml),
+            Some("json") => Some(SpecFormat::Json),
+            Some("toml") => Some(SpecFormat::Toml),
+            _ => None
+        }
+    }
 
-            None => panic!("SPEC_FILE env variable not defined!")
+    /// Returns the format of a spec definition given its content - JSON starts with a '{' and TOML with
+    /// a table header ([..]) or a key = value pair, anything else is taken to be YAML
+    pub fn detect(src: &str) -> SpecFormat {
+        let first = src.lines().map(|l| l.trim()).find(|l| !l.is_empty() && !l.starts_with('#')).unwrap_or_default();
+        let is_key = |k: &str| !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '"');
+        if first.starts_with('{') {
+            SpecFormat::Json
+        } else if first.starts_with('[') || first.split_once('=').map(|(k, _)| is_key(k.trim())).unwrap_or(false) {
+            SpecFormat::Toml
+        } else {
+            SpecFormat::Yaml
         }
+    }
+}
 
-    let mut specs=HashMap::<String,Spec>::new();
+/// This struct represents a problem found in a spec definition when it is loaded
+#[derive(Debug)]
+pub struct SpecDiagnostic {
+    /// Description of the problem
+    pub message: String,
+    /// Path of the element with the problem (for example - messages[1100 - Authorization].bitmap.pan)
+    pub path: String,
+    /// Line (1 based) in the source, if the spec was read from a source and the line could be determined
+    pub line: Option<usize>,
+}
 
-    match crate::iso8583::yaml_de::read_spec(spec_file.as_str()){
-     Ok(spec)=> specs.insert(String::from(spec.name()),spec),
-     Err(e)=> panic!(e.msg)
-    };
+/// This struct represents the failure to load a spec, it contains every problem found in the definition
+#[derive(Debug)]
+pub struct SpecError {
+    pub diagnostics: Vec<SpecDiagnostic>,
+}
 
-    specs
-};
+impl SpecError {
+    pub(in crate::iso8583) fn new(path: &str, msg: &str, line: Option<usize>) -> SpecError {
+        SpecError { diagnostics: vec![SpecDiagnostic { message: msg.to_string(), path: path.to_string(), line }] }
+    }
 }
 
-/// This struct is the definition of the specification - layout of fields etc..
-pub struct Spec {
-    pub(in crate::iso8583) name: String,
-    #[allow(dead_code)]
-    pub(in crate::iso8583) id: u32,
-    pub(in crate::iso8583) messages: Vec<MessageSegment>,
-    pub(in crate::iso8583) header_fields: Vec<Box<dyn Field>>,
+impl Display for SpecDiagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+        f.write_str(&self.message)
+    }
 }
 
+impl Display for SpecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid spec ({} problem(s))", self.diagnostics.len())?;
+        for d in &self.diagnostics {
+            write!(f, "\n  {}", d)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SpecError {}
+
 /// This struct represents a segment in the Spec (a auth request, a response etc)
 pub struct MessageSegment {
     pub(in crate::iso8583) name: String,
     #[allow(dead_code)]
     pub(in crate::iso8583) id: u32,
-    pub(in crate::iso8583) selector: Vec<String>,
+    /// The selectors of the segment (see selector), these are verified to be valid by validate_spec
+    pub(in crate::iso8583) selector: Vec<Selector>,
     pub(in crate::iso8583) fields: Vec<Box<dyn Field>>,
+    /// The presence rules of the fields of the segment (empty if the segment does not define any)
+    pub(in crate::iso8583) rules: Vec<FieldRule>,
+    /// The default and generated values of the fields of the segment
+    pub(in crate::iso8583) defaults: Vec<FieldDefault>,
 }
 
 
@@ -70,8 +144,10 @@ impl From<&YMessageSegment> for MessageSegment {
         MessageSegment {
             name: yms.name.clone(),
             id: yms.id,
-            selector: yms.selector.iter().map(|s| s.clone()).collect(),
+            selector: yms.selector.iter().filter_map(|s| Selector::parse(s).ok()).collect(),
             fields,
+            rules: yaml_de::presence_rules_of(&yms.fields),
+            defaults: yaml_de::defaults_of(&yms.fields),
         }
     }
 }
@@ -86,30 +162,153 @@ impl MessageSegment {
 
     /// Returns a field given it's name if defined in the spec or a IsoError if the field is not found
     pub fn field_by_name(&self, name: &String) -> Result<&dyn Field, IsoError> {
-        match self.fields.iter().find(|field| -> bool{
-            if field.name() == name {
-                true
-            } else {
-                false
-            }
-        }) {
+        match self.fields.iter().find(|field| field.name() == name) {
             None => {
                 //try bitmap
-                let bmp = self.field_by_name(&"bitmap".to_string()).unwrap();
-                Ok(bmp.child_by_name(name))
+                match self.fields.iter().find(|field| field.name() == "bitmap").and_then(|bmp| bmp.child_by_name(name)) {
+                    Some(f) => Ok(f),
+                    None => Err(IsoError::FieldNotFound(format!("{} in {}", name, self.name)))
+                }
             }
             Some(f) => {
                 Ok(f.as_ref())
             }
         }
     }
+
+    /// Returns the field at the position in the bitmap or a IsoError if the position is not defined
+    pub fn field_by_pos(&self, pos: u32) -> Result<&dyn Field, IsoError> {
+        match self.fields.iter().find(|field| field.name() == "bitmap").and_then(|bmp| bmp.child_by_pos(pos)) {
+            Some(f) => Ok(f),
+            None => Err(IsoError::FieldNotFound(format!("position {} in {}", pos, self.name)))
+        }
+    }
+
+    /// Returns the fields along a path, starting with the top level (or bitmap) field and ending with the addressed
+    /// field, or a IsoError if the path doesn't resolve. A path is a field name or bitmap position optionally followed
+    /// by the names or positions of subfields separated by a '.' (for example - "48.3" or "private_1.merchant_id")
+    pub fn field_path(&self, path: &str) -> Result<Vec<&dyn Field>, IsoError> {
+        let mut parts = path.split('.');
+        let first = parts.next().unwrap_or_default();
+
+        let mut chain = match first.parse::<u32>() {
+            Ok(pos) => vec![self.field_by_pos(pos)?],
+            Err(_) => {
+                let mut chain = vec![];
+                let fields: Vec<&dyn Field> = self.fields.iter().map(|f| f.as_ref()).collect();
+                if !find_path(&fields, first, &mut chain) {
+                    return Err(IsoError::FieldNotFound(format!("{} in {}", path, self.name)));
+                }
+                //the bitmap is not part of the path of its children
+                if chain.len() > 1 && chain[0].name() == "bitmap" {
+                    chain.remove(0);
+                }
+                chain
+            }
+        };
+
+        for part in parts {
+            let parent = chain[chain.len() - 1];
+            if let Some(c) = child_by_part(parent, part) {
+                chain.push(c);
+                continue;
+            }
+
+            // subfields of a nested bitmap (like DE127.2) are addressed as subfields of the enclosing field
+            match parent.children().into_iter().filter(|c| c.as_bmp().is_some())
+                .find_map(|b| child_by_part(b, part).map(|c| (b, c))) {
+                Some((b, c)) => {
+                    chain.push(b);
+                    chain.push(c);
+                }
+                None => return Err(IsoError::FieldNotFound(format!("{} in {}", path, self.name)))
+            }
+        }
+        Ok(chain)
+    }
+}
+
+/// Returns the child of field given its position or name
+fn child_by_part<'a>(field: &'a dyn Field, part: &str) -> Option<&'a dyn Field> {
+    match part.parse::<u32>() {
+        Ok(pos) => field.child_by_pos(pos),
+        Err(_) => field.child_by_name(&part.to_string())
+    }
+}
+
+/// Returns the tag in wire format as per the format of tlv_field
+fn tag_to_raw(tlv_field: &TlvField, tag: &str) -> Result<Vec<u8>, IsoError> {
+    tlv_field.tag_to_raw(tag).ok_or_else(|| IsoError::Other(format!("invalid tag - {}", tag)))
+}
+
+/// Returns the values of the subfields of f parsed from raw (the value of f)
+fn parse_children_of(f: &dyn Field, raw: &[u8]) -> Result<HashMap<String, Vec<u8>>, ParseError> {
+    let mut children = HashMap::new();
+    let mut in_buf = Cursor::new(raw);
+    in_buf.set_position(raw.len() as u64);
+    parse_children(&f.children(), &in_buf, 0, &mut children, &mut ParseTrace::new(false))?;
+    Ok(children)
+}
+
+/// Searches fields (and their children) for a field with the given name and collects the fields
+/// along the way into chain, returns true if the field is found
+fn find_path<'a>(fields: &[&'a dyn Field], name: &str, chain: &mut Vec<&'a dyn Field>) -> bool {
+    for f in fields {
+        chain.push(*f);
+        if f.name() == name || find_path(&f.children(), name, chain) {
+            return true;
+        }
+        chain.pop();
+    }
+    false
 }
 
 impl Spec {
+    /// Creates a spec from its definition in a file, the format is determined by the extension of the file
+    /// (see SpecFormat::from_path) or its content. Problems are reported with their line for a YAML file only,
+    /// in a JSON or TOML file just syntax errors have a line
+    pub fn from_file(spec_file: &str) -> Result<Spec, SpecError> {
+        yaml_de::read_spec(spec_file)
+    }
+
+    /// Creates a spec from its YAML definition in a string (for example, one embedded via include_str!). A base
+    /// spec (see extends) is read relative to the current directory of the process, so a spec that extends
+    /// another is better read with Spec::from_file (relative to the spec file)
+    pub fn from_yaml_str(yaml: &str) -> Result<Spec, SpecError> {
+        yaml_de::read_spec_from_str(yaml)
+    }
+
+    /// Creates a spec from its YAML definition in a byte slice (for example, one embedded via include_bytes!)
+    pub fn from_yaml_slice(yaml: &[u8]) -> Result<Spec, SpecError> {
+        yaml_de::read_spec_from_slice(yaml)
+    }
+
+    /// Creates a spec by reading its YAML definition from a reader
+    pub fn from_reader<R: Read>(reader: R) -> Result<Spec, SpecError> {
+        yaml_de::read_spec_from_reader(reader)
+    }
+
+    /// Creates a spec from its definition in a string in the given format, a base spec (see extends) is read
+    /// relative to the current directory of the process
+    pub fn from_str_as(src: &str, format: SpecFormat) -> Result<Spec, SpecError> {
+        yaml_de::read_spec_from_str_as(src, format)
+    }
+
+    /// Returns the definition of the spec in the given format (with all refs and base specs resolved)
+    pub fn export(&self, format: SpecFormat) -> Result<String, SpecError> {
+        yaml_de::write_spec(&self.definition, format)
+    }
+
+    /// Returns the name of the spec
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// Returns the id of the spec
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
     /// Returns a message segment given its name or a IsoError if such a segment is not present
     pub fn get_message(&self, name: &str) -> Result<&MessageSegment, IsoError> {
         for msg in &self.messages {
@@ -117,55 +316,80 @@ impl Spec {
                 return Ok(msg);
             }
         }
-        return Err(IsoError { msg: format!("{} message not found", name) });
+        return Err(IsoError::MessageNotFound(name.to_string()));
     }
 
     /// Returns a message that corresponds to the given header value or an IsoError if such a selector
-    /// doesn't exist
+    /// doesn't exist. Selectors with conditions on fields of the message are not considered (see get_msg_segment)
     pub fn get_message_from_header(&self, header_val: &str) -> Result<&MessageSegment, IsoError> {
-        for msg in &self.messages {
-            if msg.selector.contains(&header_val.to_string()) {
-                return Ok(msg);
-            }
+        match self.select(header_val, |_, _| false) {
+            Some(msg) => Ok(msg),
+            None => Err(IsoError::MessageNotFound(format!("header {}", header_val)))
         }
-        return Err(IsoError { msg: format!("message not found for header - {}", header_val) });
+    }
+
+    /// Returns the segment with the most specific selector (see selector) that matches header, a selector with
+    /// conditions only matches if matches_fields returns true for it
+    fn select<F>(&self, header: &str, matches_fields: F) -> Option<&MessageSegment> where F: Fn(&MessageSegment, &Selector) -> bool {
+        let mut candidates = self.messages.iter()
+            .flat_map(|msg| msg.selector.iter().map(move |s| (msg, s)))
+            .filter(|(_, s)| s.matches_header(header))
+            .collect::<Vec<_>>();
+        // the sort is stable, so the first defined of equally specific selectors comes first
+        candidates.sort_by_key(|(_, s)| std::cmp::Reverse(s.specificity()));
+        candidates.into_iter()
+            .find(|(msg, s)| !s.has_conditions() || matches_fields(msg, s))
+            .map(|(msg, _)| msg)
     }
 
     /// Returns a segment by first parsing the header field and then matching the header value against
-    /// the selector
-    pub fn get_msg_segment(&'static self, data: &Vec<u8>) -> Result<&MessageSegment, IsoError> {
-        let mut selector = String::new();
+    /// the selector. The conditions of a selector are checked against the fields of data parsed as per the
+    /// segment of the selector
+    pub fn get_msg_segment(&self, data: &[u8]) -> Result<&MessageSegment, ParseError> {
+        self.msg_segment(data, &mut ParseTrace::new(false))
+    }
+
+    fn msg_segment(&self, data: &[u8], trace: &mut ParseTrace) -> Result<&MessageSegment, ParseError> {
+        let selector = self.parse_header(data, trace)?.concat();
+
+        debug!("computed header value for incoming message = {}", selector);
+        let matches_fields = |msg: &MessageSegment, s: &Selector| {
+            // the fields parsed before a failure (if any) are checked as well
+            let mut iso_msg = empty_msg(self, msg);
+            let _ = parse_fields(&mut iso_msg, data, &mut ParseTrace::new(false));
+            s.matches_fields(|path| iso_msg.get(path).ok())
+        };
+        match self.select(selector.as_str(), matches_fields) {
+            Some(msg) => Ok(msg),
+            None => Err(ParseError::NoMatchingSegment { header: selector })
+        }
+    }
+
+    /// Returns the values of the header fields parsed from the start of data
+    fn parse_header(&self, data: &[u8], trace: &mut ParseTrace) -> Result<Vec<String>, ParseError> {
+        let mut header = vec![];
         let mut f2d_map = HashMap::new();
 
         let mut in_buf = Cursor::new(data);
 
         for f in &self.header_fields {
-            match f.parse(&mut in_buf, &mut f2d_map) {
-                Ok(_) => {
-                    selector.extend(f.to_string(f2d_map.get(f.name()).unwrap()).chars());
-                }
-                Err(e) => {
-                    return Err(IsoError { msg: e.msg });
-                }
-            }
-        }
-
-        debug!("computed header value for incoming message = {}", selector);
-        match self.get_message_from_header(selector.as_str()) {
-            Ok(msg) => {
-                Ok(msg)
+            let offset = in_buf.position() as usize;
+            f.parse(&mut in_buf, &mut f2d_map, trace)?;
+            match f2d_map.get(f.name()) {
+                Some(v) => header.push(f.to_string(v)),
+                None => return Err(ParseError::InvalidEncoding { field: f.name().clone(), offset })
             }
-            Err(e) => Err(e)
         }
+        Ok(header)
     }
 }
 
 /// This struct represents a parsed message for a given spec
-pub struct IsoMsg {
+pub struct IsoMsg<'a> {
     // The spec associated with this IsoMsg
-    pub spec: &'static Spec,
+    pub spec: &'a Spec,
     /// The segment that the IsoMsg represents
-    pub msg: &'static MessageSegment,
+    pub msg: &'a MessageSegment,
     /// field data map - name to raw value
     pub fd_map: std::collections::HashMap<String, Vec<u8>>,
     /// the bitmap on the iso message
@@ -173,25 +397,18 @@ pub struct IsoMsg {
 }
 
 /// Operations on IsoMsg
-impl IsoMsg {
-    pub fn spec(&self) -> &'static Spec {
+impl<'a> IsoMsg<'a> {
+    /// Returns the spec associated with this IsoMsg
+    pub fn spec(&self) -> &'a Spec {
         self.spec
     }
 
     /// Returns the value of a field by position in the bitmap
     pub fn bmp_child_value(&self, pos: u32) -> Result<String, IsoError> {
-        let f = self.msg.fields.iter().find(|f| -> bool {
-            if f.name() == "bitmap" {
-                true
-            } else {
-                false
-            }
-        }).unwrap();
-
-        let cf = f.child_by_pos(pos);
+        let cf = self.msg.field_by_pos(pos)?;
         match self.fd_map.get(cf.name()) {
             None => {
-                Err(IsoError { msg: format!("no value for field at position {}", pos) })
+                Err(IsoError::FieldNotSet(format!("position {}", pos)))
             }
             Some(v) => {
                 Ok(cf.to_string(v))
@@ -201,77 +418,298 @@ impl IsoMsg {
 
     /// Returns the value of a top level field like message_type
     pub fn get_field_value(&self, name: &String) -> Result<String, IsoError> {
-        match self.msg.fields.iter().find(|f| -> bool {
-            if f.name() == name {
-                true
-            } else {
-                false
-            }
-        }) {
+        match self.msg.fields.iter().find(|f| f.name() == name) {
             Some(f) => {
-                Ok(f.to_string(self.fd_map.get(name).unwrap()))
+                match self.fd_map.get(name) {
+                    Some(v) => Ok(f.to_string(v)),
+                    None => Err(IsoError::FieldNotSet(name.clone()))
+                }
             }
             None => {
-                Err(IsoError { msg: format!("No such field : {}", name) })
+                Err(IsoError::FieldNotFound(name.clone()))
             }
         }
     }
 
-    /// sets a top-level field like message_type etc
-    pub fn set(&mut self, name: &str, val: &str) -> Result<(), IsoError> {
-        match self.msg.field_by_name(&name.to_string()) {
-            Ok(f) => {
-                self.fd_map.insert(f.name().clone(), f.to_raw(val));
-                Ok(())
-            }
-            Err(e) => Err(e)
+    /// Returns the value of a field given its path (see MessageSegment::field_path), for example - "message_type",
+    /// "48.3" or "private_1.merchant_id"
+    pub fn get(&self, path: &str) -> Result<String, IsoError> {
+        let chain = self.msg.field_path(path)?;
+        let f = chain[chain.len() - 1];
+        match self.fd_map.get(f.name()) {
+            Some(v) => Ok(f.to_string(v)),
+            None => Err(IsoError::FieldNotSet(path.to_string()))
+        }
+    }
+
+    /// Sets a field given its path (see MessageSegment::field_path) like message_type or "48.3". Setting a subfield
+    /// rebuilds the value of its parent fields and setting a field in the bitmap turns on its position. The value
+    /// is checked against the content rules of the field. A bitmap cannot be set, it follows the fields that are set
+    pub fn set(&mut self, path: &str, val: &str) -> Result<(), IsoError> {
+        let chain = self.msg.field_path(path)?;
+        self.set_checked(&chain, val)
+    }
+
+    /// Returns the state of a bitmap given its path or the path of the field that contains it (for a nested
+    /// bitmap like the one within DE127)
+    pub fn bitmap_of(&self, path: &str) -> Result<Bitmap, IsoError> {
+        let chain = self.msg.field_path(path)?;
+        let f = chain[chain.len() - 1];
+        match f.as_bmp().or_else(|| f.children().into_iter().find_map(|c| c.as_bmp())) {
+            Some(bmp_field) => Ok(bmp_field.bitmap(self)),
+            None => Err(IsoError::Other(format!("{} is not a bitmapped field", path)))
+        }
+    }
+
+    /// Returns the data elements of a Tlv (or AsciiTlv) field given its path (see MessageSegment::field_path)
+    pub fn tlv(&self, path: &str) -> Result<Vec<Tlv>, IsoError> {
+        let (_, tlv_field) = self.tlv_path(path)?;
+        self.elements_of(tlv_field)?.ok_or_else(|| IsoError::FieldNotSet(path.to_string()))
+    }
+
+    /// Returns the tags and values (as strings) of the top level data elements of a Tlv (or AsciiTlv) field
+    /// in the order they appear in the field
+    pub fn tag_values(&self, path: &str) -> Result<Vec<(String, String)>, IsoError> {
+        let (_, tlv_field) = self.tlv_path(path)?;
+        Ok(self.tlv(path)?.iter().map(|e| (tlv_field.tag_to_string(&e.tag), tlv_field.value_to_string(&e.value))).collect())
+    }
+
+    /// Returns the value of a tag in a Tlv (or AsciiTlv) field given its path. For BER-TLV the tag (like 9F26 or 95) and
+    /// the value are hex strings, otherwise they are as per the tag encoding and data encoding of the field
+    pub fn get_tag(&self, path: &str, tag: &str) -> Result<String, IsoError> {
+        let (_, tlv_field) = self.tlv_path(path)?;
+        let elements = self.tlv(path)?;
+        match find_tag(&elements, &tag_to_raw(tlv_field, tag)?) {
+            Some(e) => Ok(tlv_field.value_to_string(&e.value)),
+            None => Err(IsoError::FieldNotSet(format!("{}.{}", path, tag)))
+        }
+    }
+
+    /// Sets the value of a tag in a Tlv (or AsciiTlv) field given its path (see get_tag for the format of the tag and
+    /// value). Tags that are not present in the field are appended to it, so the field is assembled in a deterministic order
+    pub fn set_tag(&mut self, path: &str, tag: &str, val: &str) -> Result<(), IsoError> {
+        let (chain, tlv_field) = self.tlv_path(path)?;
+        let mut elements = self.elements_of(tlv_field)?.unwrap_or_default();
+
+        let invalid_value = || IsoError::Other(format!("invalid value for tag {} - {}", tag, val));
+        let value = tlv_field.value_to_raw(val).ok_or_else(invalid_value)?;
+        tlv_field.set_element(&mut elements, &tag_to_raw(tlv_field, tag)?, &value).map_err(|_| invalid_value())?;
+        let data = tlv_field.assemble_elements(&elements).map_err(|_| invalid_value())?;
+        self.set_raw(&chain, data)
+    }
+
+    fn tlv_path(&self, path: &str) -> Result<(Vec<&'a dyn Field>, &'a TlvField), IsoError> {
+        let chain = self.msg.field_path(path)?;
+        match chain[chain.len() - 1].as_tlv() {
+            Some(tlv_field) => Ok((chain, tlv_field)),
+            None => Err(IsoError::Other(format!("{} is not a tlv field", path)))
         }
     }
 
-    /// Sets a field in the bitmap with the given value
+    /// Returns the data elements of the field or None if it is not set
+    fn elements_of(&self, tlv_field: &TlvField) -> Result<Option<Vec<Tlv>>, IsoError> {
+        match self.fd_map.get(tlv_field.name()) {
+            Some(v) => tlv_field.parse_elements(v).map(Some)
+                .map_err(|offset| IsoError::Parse(ParseError::InvalidEncoding { field: tlv_field.name().clone(), offset })),
+            None => Ok(None)
+        }
+    }
+
+    /// Sets a field in the bitmap with the given value (checked against the content rules of the field)
     pub fn set_on(&mut self, pos: u32, val: &str) -> Result<(), IsoError> {
-        match self.msg.field_by_name(&"bitmap".to_string()) {
-            Ok(f) => {
-                let cf = f.child_by_pos(pos);
-                self.fd_map.insert(cf.name().clone(), cf.to_raw(val));
-                self.bmp.set_on(pos);
-                Ok(())
+        let cf = self.msg.field_by_pos(pos)?;
+        self.set_checked(&[cf], val)
+    }
+
+    /// Sets the value of the last field in chain after checking it against the content rules of the field
+    fn set_checked(&mut self, chain: &[&'a dyn Field], val: &str) -> Result<(), IsoError> {
+        let f = chain[chain.len() - 1];
+        if f.as_bmp().is_some() {
+            return Err(IsoError::Other(format!("{} is a bitmapped field, it is set along with its fields", f.name())));
+        }
+        f.check(val).map_err(|error| IsoError::InvalidContent { field: f.name().clone(), error })?;
+        self.set_raw(chain, f.to_raw(val))
+    }
+
+    /// Sets the raw value of the last field in chain, the subfields of that field are parsed from the value
+    /// and its parents are rebuilt from their subfields. The message is unchanged if the subfields cannot be
+    /// parsed from the value
+    fn set_raw(&mut self, chain: &[&'a dyn Field], raw: Vec<u8>) -> Result<(), IsoError> {
+        let f = chain[chain.len() - 1];
+        let children = parse_children_of(f, &raw)?;
+        self.remove_children_of(f);
+        self.fd_map.insert(f.name().clone(), raw);
+        self.fd_map.extend(children);
+
+        for i in (0..chain.len() - 1).rev() {
+            let parent = chain[i];
+            if let Some(bmp_field) = parent.as_bmp() {
+                // a nested bitmap tracks which of its subfields are set
+                let child = chain[i + 1];
+                let mut bmp = bmp_field.bitmap(self);
+                if self.fd_map.contains_key(child.name()) {
+                    bmp.set_on(child.position());
+                } else {
+                    bmp.set_off(child.position());
+                }
+                self.fd_map.insert(parent.name().clone(), bmp.as_vec());
+                continue;
+            }
+
+            let mut data = vec![];
+            match parent.children().iter().try_for_each(|c| c.assemble(&mut data, self).map(|_| ())) {
+                Ok(_) => self.fd_map.insert(parent.name().clone(), data),
+                //not all subfields are available (yet)
+                Err(_) => self.fd_map.remove(parent.name())
+            };
+        }
+
+        let root = chain[0];
+        if root.position() > 0 && self.msg.field_by_pos(root.position()).map(|f| f.name() == root.name()).unwrap_or(false) {
+            if self.fd_map.contains_key(root.name()) {
+                self.bmp.set_on(root.position());
+            } else {
+                self.bmp.set_off(root.position());
             }
-            Err(e) => Err(e)
         }
+        Ok(())
     }
 
-    /// Echoes (sets the value with the identical field in req_msg) for given positions in the bitmap
+    fn remove_children_of(&mut self, f: &dyn Field) {
+        for c in f.children() {
+            self.fd_map.remove(c.name());
+            self.remove_children_of(c);
+        }
+    }
+
+    /// Unsets the field at the position in the bitmap i.e removes its value and turns off the position
+    pub fn unset(&mut self, pos: u32) -> Result<(), IsoError> {
+        let cf = self.msg.field_by_pos(pos)?;
+        self.fd_map.remove(cf.name());
+        self.remove_children_of(cf);
+        self.bmp.set_off(pos);
+
+        //keep the bitmap of a parsed message in sync
+        if self.fd_map.contains_key("bitmap") {
+            self.fd_map.insert("bitmap".to_string(), self.bmp.as_vec());
+        }
+        Ok(())
+    }
+
+    /// Echoes (sets the value with the identical field in req_msg) for given positions in the bitmap, the values are
+    /// checked against the content rules of the fields of this message
     pub fn echo_from(&mut self, req_msg: &IsoMsg, positions: &[u32]) -> Result<(), IsoError> {
-        match self.msg.field_by_name(&"bitmap".to_string()) {
-            Ok(f) => {
-                for pos in positions {
-                    let cf = f.child_by_pos(*pos);
-                    match req_msg.bmp_child_value(*pos) {
-                        Ok(res) => {
-                            debug!("echoing .. {}: {}", pos, res);
-                            self.fd_map.insert(cf.name().clone(), cf.to_raw(res.as_str()));
-                            self.bmp.set_on(*pos);
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
-                    }
+        for pos in positions {
+            let cf = self.msg.field_by_pos(*pos)?;
+            let res = req_msg.bmp_child_value(*pos)?;
+            debug!("echoing .. {}: {}", pos, res);
+            self.set_checked(&[cf], res.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Returns a new response to this (request) message. The header of the response is that of the request with its
+    /// message type (the last header field with a request message type) replaced by the response message type (see
+    /// defaults::response_mti), its default and generated values are filled in and its echo fields are copied from
+    /// the request
+    pub fn response(&self) -> Result<IsoMsg<'a>, IsoError> {
+        // the header is parsed from the leading fields (those before the bitmap) of the request, like the header
+        // of a message being parsed
+        let mut leading = vec![];
+        for f in self.msg.fields.iter().take_while(|f| f.as_bmp().is_none()) {
+            f.assemble(&mut leading, self)?;
+        }
+        let mut header = self.spec.parse_header(&leading, &mut ParseTrace::new(false))?;
+        let req_header = header.concat();
+        // a header like a TPDU precedes the message type, so the last value that is a request message type is used
+        match header.iter_mut().rev().find_map(|v| response_mti(v).map(|mti| (v, mti))) {
+            Some((v, mti)) => *v = mti,
+            None => return Err(IsoError::Other(format!("{} is not a request message type", req_header)))
+        }
+        let resp_header = header.concat();
+
+        // the conditions of the selectors (like a processing code) are checked against the fields of the request
+        let segment = self.spec.select(&resp_header, |_, s| s.matches_fields(|path| self.get(path).ok()))
+            .ok_or_else(|| IsoError::MessageNotFound(format!("header {}", resp_header)))?;
+        let mut resp = new_msg(self.spec, segment);
+        resp.set_header(&header)?;
+        let msg = resp.msg;
+        for d in msg.defaults.iter().filter(|d| d.echo) {
+            if let (Ok(val), Ok(chain)) = (self.get(&d.field), msg.field_path(&d.field)) {
+                resp.set_checked(&chain, &val)?;
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Sets the leading fields (those before the bitmap) of the message from the values of the header fields
+    fn set_header(&mut self, header: &[String]) -> Result<(), IsoError> {
+        let mut scratch = empty_msg(self.spec, self.msg);
+        let mut data = vec![];
+        for (f, val) in self.spec.header_fields.iter().zip(header) {
+            f.check(val).map_err(|error| IsoError::InvalidContent { field: f.name().clone(), error })?;
+            scratch.fd_map.insert(f.name().clone(), f.to_raw(val));
+            f.assemble(&mut data, &scratch)?;
+        }
+
+        let mut in_buf = Cursor::new(data.as_slice());
+        let msg = self.msg;
+        for f in msg.fields.iter().take_while(|f| f.as_bmp().is_none()) {
+            if in_buf.position() as usize == data.len() {
+                break;
+            }
+            f.parse(&mut in_buf, &mut self.fd_map, &mut ParseTrace::new(false))?;
+        }
+        Ok(())
+    }
+
+    /// Sets the default and generated values of the fields of the segment
+    fn fill_defaults(&mut self) {
+        let now = Utc::now();
+        let mut stan = None;
+        let msg = self.msg;
+        for d in &msg.defaults {
+            let val = match (&d.default, d.generator) {
+                (_, Some(Generator::TransmissionTime)) => Generator::TransmissionTime.generate(&now, ""),
+                (_, Some(generator)) => generator.generate(&now, stan.get_or_insert_with(|| self.spec.stan.next())),
+                (Some(default), None) => default.clone(),
+                (None, None) => continue,
+            };
+            if let Ok(chain) = msg.field_path(&d.field) {
+                if let Err(e) = self.set_checked(&chain, &val) {
+                    error!("default value of {} is not valid - {}", d.field, e);
                 }
-                Ok(())
             }
-            Err(e) => Err(e)
         }
     }
 
-    /// Assembles the messages into a Vec<u8> or a IsoError on failure
+    /// Returns all fields that break the presence rules (mandatory, conditional etc) of the message segment. When
+    /// the segment defines rules, fields of its bitmaps without a rule are not allowed
+    pub fn validate(&self) -> Result<(), Vec<Violation>> {
+        match check_rules(self, None) {
+            violations if violations.is_empty() => Ok(()),
+            violations => Err(violations)
+        }
+    }
+
+    /// Same as validate, but also checks that the echo fields of req_msg are echoed in this (response) message
+    pub fn validate_response(&self, req_msg: &IsoMsg) -> Result<(), Vec<Violation>> {
+        match check_rules(self, Some(req_msg)) {
+            violations if violations.is_empty() => Ok(()),
+            violations => Err(violations)
+        }
+    }
+
+    /// Assembles the messages into a Vec<u8> or a IsoError on failure (including a message that
+    /// breaks the presence rules of its segment, see validate)
     pub fn assemble(&self) -> Result<Vec<u8>, IsoError> {
+        self.validate().map_err(|v| IsoError::Assemble(ParseError::RuleViolations(v)))?;
         let mut out_buf: Vec<u8> = Vec::new();
         for f in &self.msg.fields {
             match f.assemble(&mut out_buf, &self) {
                 Ok(_) => {}
                 Err(e) => {
-                    return Err(IsoError { msg: e.msg });
+                    return Err(IsoError::Assemble(e));
                 }
             }
         }
@@ -281,7 +719,7 @@ impl IsoMsg {
     /// Sets F52 based on provided clear pin, and format, key provided via cfg
     pub fn set_pin(&mut self, pin: &str, pan: &str, cfg: &Config) -> Result<(), IsoError> {
         if cfg.get_pin_fmt().is_none() || cfg.get_pin_key().is_none() {
-            return Err(IsoError { msg: format!("missing pin_format or key in call to set_pin") });
+            return Err(IsoError::MissingConfig("pin_format or key in call to set_pin".to_string()));
         }
 
         match generate_pin_block(&cfg.get_pin_fmt().as_ref().unwrap(), pin, pan, cfg.get_pin_key().as_ref().unwrap().as_str()) {
@@ -289,7 +727,7 @@ impl IsoMsg {
                 self.set_on(52, hex::encode(v).as_str())
             }
             Err(e) => {
-                Err(IsoError { msg: e.msg })
+                Err(e.into())
             }
         }
     }
@@ -297,14 +735,14 @@ impl IsoMsg {
     /// Sets F64 or F128 based on algo, padding and key provided via cfg
     pub fn set_mac(&mut self, cfg: &Config) -> Result<(), IsoError> {
         if cfg.get_mac_algo().is_none() || cfg.get_mac_padding().is_none() || cfg.get_mac_key().is_none() {
-            return Err(IsoError { msg: format!("missing mac_algo or padding or key in call to set_mac") });
+            return Err(IsoError::MissingConfig("mac_algo or padding or key in call to set_mac".to_string()));
         }
 
 
         if self.bmp.is_on(1) {
-            self.set_on(128,"0000000000000000");
+            self.set_on(128,"0000000000000000")?;
         } else {
-            self.set_on(64,"0000000000000000");
+            self.set_on(64,"0000000000000000")?;
         }
 
 
@@ -320,17 +758,16 @@ impl IsoMsg {
         debug!("generating mac on: {}", hex::encode(&data));
 
         match generate_mac(&cfg.get_mac_algo().as_ref().unwrap(), &cfg.get_mac_padding().as_ref().unwrap(),
-                           &data[0..data.len()-8].to_vec(), &hex::decode(cfg.get_mac_key().as_ref().unwrap()).unwrap()) {
+                           &data[0..data.len()-8].to_vec(), &decode_key(cfg.get_mac_key().as_ref().unwrap()).map_err(MacError::from)?) {
             Ok(v) => {
                 if self.bmp.is_on(1) {
-                    self.set_on(128, hex::encode(v).as_str());
+                    self.set_on(128, hex::encode(v).as_str())
                 } else {
-                    self.set_on(64, hex::encode(v).as_str());
+                    self.set_on(64, hex::encode(v).as_str())
                 }
-                Ok(())
             }
             Err(e) => {
-                Err(IsoError { msg: e.msg })
+                Err(e.into())
             }
         }
     }
@@ -341,7 +778,7 @@ fn collect_children(f: &dyn Field, ordered_fields: &mut Vec<String>) {
     f.children().iter().for_each(|f| collect_children(*f, ordered_fields));
 }
 
-impl Display for IsoMsg {
+impl Display for IsoMsg<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         let mut res = "".to_string();
         let mut ordered_fields = vec![];
@@ -349,9 +786,7 @@ impl Display for IsoMsg {
 
         res = res + format!("\n{:20.40} : {:5}  : {} ", "-Field-", "-Position-", "-Field Value-").as_str();
         for f in ordered_fields {
-            if self.fd_map.contains_key(f.as_str()) {
-                let field = self.msg.field_by_name(&f).unwrap();
-                let field_value = &self.fd_map.get(f.as_str()).unwrap();
+            if let (Ok(field), Some(field_value)) = (self.msg.field_by_name(&f), self.fd_map.get(f.as_str())) {
                 let mut pos_str: String = String::new();
                 if field.position() > 0 {
                     pos_str = format!("{:03}", field.position());
@@ -366,14 +801,21 @@ impl Display for IsoMsg {
     }
 }
 
-/// Returns a spec given its name
-pub fn spec(_name: &str) -> &'static Spec {
-    //TODO:: handle case of multiple specs, for now just return the first
-    ALL_SPECS.iter().find_map(|(_k, v)| Some(v)).unwrap()
+/// Returns a spec given its name from the default registry or a IsoError if no such spec is registered
+pub fn spec(name: &str) -> Result<Arc<Spec>, IsoError> {
+    default_registry().by_name(name)
+}
+
+/// Returns a new IsoMsg that can be used to create a message, the default and generated values (like the
+/// STAN) defined in the segment are filled in
+pub fn new_msg<'a>(spec: &'a Spec, seg: &'a MessageSegment) -> IsoMsg<'a> {
+    let mut iso_msg = empty_msg(spec, seg);
+    iso_msg.fill_defaults();
+    iso_msg
 }
 
-/// Returns a empty IsoMsg that can be used to create a message
-pub fn new_msg(spec: &'static Spec, seg: &'static MessageSegment) -> IsoMsg {
+/// Returns a IsoMsg without any fields set (used to parse a message)
+fn empty_msg<'a>(spec: &'a Spec, seg: &'a MessageSegment) -> IsoMsg<'a> {
     IsoMsg {
         spec,
         msg: seg,
@@ -383,40 +825,541 @@ pub fn new_msg(spec: &'static Spec, seg: &'static MessageSegment) -> IsoMsg {
 }
 
 impl Spec {
-    /// Returns a IsoMsg after parsing data or an ParseError on failure
-    pub fn parse(&'static self, data: &mut Vec<u8>) -> Result<IsoMsg, ParseError> {
-        let msg = self.get_msg_segment(data);
-        if msg.is_err() {
-            return Err(ParseError { msg: msg.err().unwrap().msg });
-        }
-
-        let mut iso_msg = IsoMsg {
-            spec: &self,
-            msg: &msg.unwrap(),
-            fd_map: HashMap::new(),
-            bmp: Bitmap::new(0, 0, 0),
+    /// Returns a IsoMsg after parsing data or an ParseError on failure (including a message that breaks the
+    /// presence rules of its segment). Any data left after the last field is ignored
+    pub fn parse(&self, data: &[u8]) -> Result<IsoMsg<'_>, ParseError> {
+        self.parse_with_len(data).map(|(iso_msg, _)| iso_msg)
+    }
+
+    /// Returns a IsoMsg after parsing data or an ParseError on failure, a ParseError::TrailingData is
+    /// returned if any data is left after the last field
+    pub fn parse_strict(&self, data: &[u8]) -> Result<IsoMsg<'_>, ParseError> {
+        let (iso_msg, consumed) = self.parse_with_len(data)?;
+        if consumed < data.len() {
+            return Err(ParseError::TrailingData { offset: consumed, remaining: data.len() - consumed });
+        }
+        Ok(iso_msg)
+    }
+
+    /// Returns a IsoMsg along with the number of bytes of data that were consumed to parse it
+    /// or an ParseError on failure
+    pub fn parse_with_len(&self, data: &[u8]) -> Result<(IsoMsg<'_>, usize), ParseError> {
+        let (iso_msg, consumed) = self.parse_fields(data)?;
+        iso_msg.validate().map_err(ParseError::RuleViolations)?;
+        Ok((iso_msg, consumed))
+    }
+
+    /// Returns a IsoMsg after parsing data without checking the presence rules of its segment (so that the
+    /// caller can run IsoMsg::validate and, for example, decline a request that breaks them)
+    pub fn parse_unvalidated(&self, data: &[u8]) -> Result<IsoMsg<'_>, ParseError> {
+        self.parse_fields(data).map(|(iso_msg, _)| iso_msg)
+    }
+
+    fn parse_fields(&self, data: &[u8]) -> Result<(IsoMsg<'_>, usize), ParseError> {
+        let mut iso_msg = empty_msg(self, self.get_msg_segment(data)?);
+        let consumed = parse_fields(&mut iso_msg, data, &mut ParseTrace::new(false))?;
+        Ok((iso_msg, consumed))
+    }
+
+    /// Parses data while recording the offset, length indicator, raw bytes and value of every field.
+    /// Unlike parse, a ParseReport is returned even on failure, with the message parsed so far
+    pub fn parse_traced(&self, data: &[u8]) -> ParseReport<'_> {
+        let mut trace = ParseTrace::new(true);
+        let msg = match self.msg_segment(data, &mut trace) {
+            Ok(msg) => msg,
+            Err(e) => return ParseReport::new(None, trace.into_fields(), Some(e), data)
         };
 
-        let mut cp_data = Cursor::new(data);
-
-        for f in &iso_msg.msg.fields {
-            debug!("parsing field : {}", f.name());
-            let res = match f.parse(&mut cp_data, &mut iso_msg.fd_map) {
-                Err(e) => Result::Err(e),
-                Ok(_) => {
-                    //if this is "THE" bitmap, then save it on isomsg
-                    if f.name() == "bitmap" {
-                        let bmp_data = iso_msg.fd_map.get(f.name()).unwrap();
-                        iso_msg.bmp = Bitmap::from_vec(bmp_data);
-                    }
-                    Ok(())
-                }
+        let mut trace = ParseTrace::new(true);
+        let mut iso_msg = empty_msg(self, msg);
+        let error = parse_fields(&mut iso_msg, data, &mut trace)
+            .and_then(|_| iso_msg.validate().map_err(ParseError::RuleViolations)).err();
+        ParseReport::new(Some(iso_msg), trace.into_fields(), error, data)
+    }
+}
+
+/// Parses all fields of the segment of iso_msg from data and returns the number of bytes consumed. On
+/// failure iso_msg holds all fields parsed before the failure
+fn parse_fields(iso_msg: &mut IsoMsg, data: &[u8], trace: &mut ParseTrace) -> Result<usize, ParseError> {
+    let mut cp_data = Cursor::new(data);
+
+    for f in &iso_msg.msg.fields {
+        debug!("parsing field : {}", f.name());
+        let offset = cp_data.position() as usize;
+        let res = f.parse(&mut cp_data, &mut iso_msg.fd_map, trace);
+
+        //if this is "THE" bitmap, then save it on isomsg (even if one of its children failed to parse)
+        if f.name() == "bitmap" {
+            match iso_msg.fd_map.get(f.name()).map(Bitmap::from_vec) {
+                Some(Ok(bmp)) => iso_msg.bmp = bmp,
+                _ if res.is_ok() => return Err(ParseError::InvalidEncoding { field: f.name().clone(), offset }),
+                _ => {}
             };
+        }
+        res?;
+    }
+    Ok(cp_data.position() as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use std::panic::AssertUnwindSafe;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    use crate::crypto::CryptoError;
+    use crate::crypto::pin::{PinError, PinFormat};
+    use crate::iso8583::builder::SpecBuilder;
+    use crate::iso8583::config::Config;
+    use crate::iso8583::field::{ContentError, ContentType, ParseError};
+    use crate::iso8583::field::Encoding::{ASCII, BCD, BINARY};
+    use crate::iso8583::iso_spec::{IsoMsg, new_msg, Spec, SpecFormat};
+    use crate::iso8583::IsoError;
+
+    const SAMPLE_SPEC: &str = include_str!("../../sample_spec/sample_spec.yaml");
+
+    /// Returns a new 1100 message without the fields generated by the sample spec (DE7, DE11 and DE37)
+    fn new_1100(spec: &Spec) -> IsoMsg<'_> {
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("1100").unwrap());
+        for pos in [7, 11, 37] {
+            iso_msg.unset(pos).unwrap();
+        }
+        iso_msg
+    }
+
+    #[test]
+    fn test_spec_from_memory() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+        assert_eq!(spec.name(), "SampleSpec");
+        assert_eq!(spec.id(), 3);
+        assert!(spec.get_message_from_header("1420").is_ok());
+
+        let spec = Spec::from_yaml_slice(include_bytes!("../../sample_spec/sample_spec.yaml")).unwrap();
+        assert_eq!(spec.name(), "SampleSpec");
+
+        let spec = Spec::from_reader(SAMPLE_SPEC.as_bytes()).unwrap();
+        assert_eq!(spec.get_message("1100 - Authorization").unwrap().name(), "1100 - Authorization");
+
+        assert!(Spec::from_yaml_str("name: [").is_err());
+        assert!(Spec::from_yaml_slice(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn test_assemble_parse_with_owned_spec() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+        iso_msg.set_on(62, "reserved-2").unwrap();
+        iso_msg.set_on(160, "F160").unwrap();
+
+        let data = iso_msg.assemble().unwrap();
+        let parsed = spec.parse(&data).unwrap();
+
+        assert_eq!(parsed.msg.name(), "1100 - Authorization");
+        assert_eq!(parsed.bmp_child_value(2).unwrap(), "4567909845671235");
+        assert_eq!(parsed.bmp_child_value(62).unwrap(), "reserved-2");
+        assert_eq!(parsed.bmp_child_value(160).unwrap(), "F160");
+        assert!(parsed.bmp.is_on(65));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_1100(&spec);
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+        let data = iso_msg.assemble().unwrap();
 
-            if res.is_err() {
-                return Result::Err(res.err().unwrap());
+        // garbage length indicator for the pan (after message_type and the primary bitmap)
+        let mut bad = data.clone();
+        bad[12] = b'x';
+        match spec.parse(&bad) {
+            Err(ParseError::BadLengthIndicator { field, offset, .. }) => {
+                assert_eq!(field, "pan");
+                assert_eq!(offset, 12);
             }
+            other => panic!("unexpected result - {:?}", other.map(|m| m.to_string()))
         }
-        Ok(iso_msg)
+
+        // position 5 is not defined in the spec
+        let mut bad = data.clone();
+        bad[4] |= 0x08;
+        match spec.parse(&bad) {
+            Err(ParseError::UndefinedBitmapPosition { position, .. }) => assert_eq!(position, 5),
+            other => panic!("unexpected result - {:?}", other.map(|m| m.to_string()))
+        }
+
+        let bad = data[..data.len() - 1].to_vec();
+        match spec.parse(&bad) {
+            Err(ParseError::InsufficientData { field, required, available, .. }) => {
+                assert_eq!(field, "amount");
+                assert_eq!(required, 12);
+                assert_eq!(available, 11);
+            }
+            other => panic!("unexpected result - {:?}", other.map(|m| m.to_string()))
+        }
+
+        let bad = b"9999".to_vec();
+        assert!(matches!(spec.parse(&bad), Err(ParseError::NoMatchingSegment { .. })));
+    }
+
+    #[test]
+    fn test_parse_never_panics() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+        iso_msg.set_on(61, "reserved_1").unwrap();
+        iso_msg.set_on(160, "F160").unwrap();
+        let data = iso_msg.assemble().unwrap();
+
+        for i in 0..data.len() {
+            assert!(spec.parse(&data[..i]).is_err(), "parsed truncated data - {}", hex::encode(&data[..i]));
+        }
+
+        // a fixed seed, so that a failure can be reproduced
+        let mut rng = StdRng::seed_from_u64(8583);
+        for _ in 0..2000 {
+            let mut bad = data.clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let i = rng.gen_range(4, bad.len());
+                bad[i] = rng.gen();
+            }
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| spec.parse(&bad).is_ok()));
+            assert!(result.is_ok(), "parse panicked on {}", hex::encode(&bad));
+        }
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        assert!(matches!(spec.get_message("no such message"), Err(IsoError::MessageNotFound(_))));
+        assert!(matches!(spec.get_message_from_header("9999"), Err(IsoError::MessageNotFound(_))));
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        assert!(matches!(iso_msg.set("no_such_field", "1"), Err(IsoError::FieldNotFound(_))));
+        assert!(matches!(iso_msg.set_on(5, "1"), Err(IsoError::FieldNotFound(_))));
+        assert!(matches!(iso_msg.bmp_child_value(2), Err(IsoError::FieldNotSet(_))));
+        assert!(matches!(iso_msg.assemble(), Err(IsoError::Assemble(ParseError::MissingFieldData { .. }))));
+        let mut request = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        request.set("message_type", "1100").unwrap();
+        assert!(matches!(request.assemble(), Err(IsoError::Assemble(ParseError::RuleViolations(_)))));
+        iso_msg.set_on(4, "29").unwrap();
+        assert!(matches!(iso_msg.assemble(), Err(IsoError::Assemble(ParseError::MissingFieldData { .. }))));
+        assert!(matches!(iso_msg.set_pin("1234", "4111111111111111", &Config::new()), Err(IsoError::MissingConfig(_))));
+
+        let mut cfg = Config::new();
+        cfg.with_pin(PinFormat::ISO0, "e0f4".to_string());
+        match iso_msg.set_pin("1234", "4111111111111111", &cfg) {
+            Err(e @ IsoError::Pin(PinError::Crypto(CryptoError::InvalidKey(_)))) => {
+                let source = e.source().unwrap();
+                assert!(source.source().unwrap().is::<CryptoError>());
+            }
+            other => panic!("unexpected result - {:?}", other)
+        }
+
+        let e: IsoError = spec.parse(b"9999").err().unwrap().into();
+        assert!(e.source().unwrap().is::<ParseError>());
+    }
+
+    #[test]
+    fn test_parse_consumed_and_trailing_data() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+        let mut data = iso_msg.assemble().unwrap();
+        let len = data.len();
+
+        let (_, consumed) = spec.parse_with_len(&data).unwrap();
+        assert_eq!(consumed, len);
+        assert!(spec.parse_strict(&data).is_ok());
+
+        data.extend_from_slice(b"xyz");
+        let (parsed, consumed) = spec.parse_with_len(&data).unwrap();
+        assert_eq!(consumed, len);
+        assert_eq!(parsed.bmp_child_value(4).unwrap(), "000000000029");
+        assert!(spec.parse(&data).is_ok());
+
+        match spec.parse_strict(&data) {
+            Err(ParseError::TrailingData { offset, remaining }) => {
+                assert_eq!(offset, len);
+                assert_eq!(remaining, 3);
+            }
+            other => panic!("unexpected result - {:?}", other.map(|m| m.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_parse_traced() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_1100(&spec);
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+        let data = iso_msg.assemble().unwrap();
+
+        let report = spec.parse_traced(&data);
+        assert!(report.is_ok());
+        assert_eq!(report.consumed(), data.len());
+
+        let fields: Vec<(&str, u32, usize, &str)> = report.fields.iter()
+            .map(|f| (f.name.as_str(), f.position, f.offset, f.value.as_str())).collect();
+        assert_eq!(fields, vec![
+            ("message_type", 0, 0, "1100"),
+            ("bitmap", 0, 4, "5000000000000000"),
+            ("pan", 2, 12, "4567909845671235"),
+            ("amount", 4, 30, "000000000029"),
+        ]);
+        assert_eq!(report.fields[2].len_ind, b"16".to_vec());
+
+        let dump = report.hexdump();
+        assert!(dump.contains("|31313030|"));
+        assert!(dump.contains("00000004  bitmap = 5000000000000000"));
+        assert!(dump.contains("0000000c  pan (002) [len-ind: 3136] = 4567909845671235"));
+
+        // truncated message, the partial result has all fields before amount
+        let report = spec.parse_traced(&data[..data.len() - 2]);
+        assert!(matches!(report.error, Some(ParseError::InsufficientData { .. })));
+        assert_eq!(report.fields.len(), 3);
+        let partial = report.msg.as_ref().unwrap();
+        assert_eq!(partial.bmp_child_value(2).unwrap(), "4567909845671235");
+        assert!(partial.bmp.is_on(4));
+        assert!(partial.bmp_child_value(4).is_err());
+        assert!(report.hexdump().contains("<not parsed>"));
+        assert!(report.to_string().contains("error: (iso8583:: parse-error: not enough data to parse - amount"));
+
+        let report = spec.parse_traced(b"9999");
+        assert!(report.msg.is_none());
+        assert_eq!(report.fields[0].name, "hdr_msg_type");
+        assert!(matches!(report.error, Some(ParseError::NoMatchingSegment { .. })));
+    }
+
+    #[test]
+    fn test_unset() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_1100(&spec);
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+        iso_msg.set_on(96, "1234").unwrap();
+        let data = iso_msg.assemble().unwrap();
+
+        let mut parsed = spec.parse(&data).unwrap();
+        assert_eq!(parsed.bmp.iter().collect::<Vec<u32>>(), vec![2, 4, 96]);
+
+        parsed.unset(96).unwrap();
+        assert!(parsed.bmp_child_value(96).is_err());
+        assert!(!parsed.bmp.is_on(1));
+        assert!(matches!(parsed.unset(5), Err(IsoError::FieldNotFound(_))));
+
+        // without the secondary bitmap and F96 the message is 8 + 4 bytes shorter
+        let data_2 = parsed.assemble().unwrap();
+        assert_eq!(data_2.len(), data.len() - 12);
+        assert_eq!(parsed.get_field_value(&"bitmap".to_string()).unwrap(), "5000000000000000");
+
+        let reparsed = spec.parse_strict(&data_2).unwrap();
+        assert_eq!(reparsed.bmp.iter().collect::<Vec<u32>>(), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_icc_data_tags() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(4, "29").unwrap();
+        iso_msg.set_tag("icc_data", "9F26", "C2A2D6F5C1A0F19A").unwrap();
+        iso_msg.set_tag("55", "95", "0000008000").unwrap();
+        iso_msg.set_tag("55", "9F26", "0102030405060708").unwrap();
+        assert!(iso_msg.bmp.is_on(55));
+        assert_eq!(iso_msg.bmp_child_value(55).unwrap(), "9f2608010203040506070895050000008000");
+
+        let data = iso_msg.assemble().unwrap();
+        let parsed = spec.parse_strict(&data).unwrap();
+        assert_eq!(parsed.get_tag("55", "95").unwrap(), "0000008000");
+        assert_eq!(parsed.tlv("icc_data").unwrap().iter().map(|e| e.tag_hex()).collect::<Vec<String>>(), vec!["9F26", "95"]);
+        assert!(matches!(parsed.get_tag("55", "9F27"), Err(IsoError::FieldNotSet(_))));
+        assert!(parsed.get_tag("pan", "9F27").is_err());
+        assert!(iso_msg.set_tag("55", "9F2", "00").is_err());
+
+        // the icc data is verified while parsing
+        let mut bad_data = data.clone();
+        let n = bad_data.len();
+        bad_data[n - 6] = 0x06;
+        match spec.parse(&bad_data) {
+            Err(ParseError::InvalidEncoding { field, offset }) => {
+                assert_eq!(field, "icc_data");
+                assert_eq!(offset, n - 7);
+            }
+            _ => panic!("expected an invalid encoding error")
+        }
+    }
+
+    #[test]
+    fn test_additional_data_tags() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(4, "29").unwrap();
+        iso_msg.set_on(48, "1003ABC2201Y").unwrap();
+        iso_msg.set_tag("48", "95", "PROMO").unwrap();
+        iso_msg.set_tag("additional_data", "10", "XYZ9").unwrap();
+        assert_eq!(iso_msg.bmp_child_value(48).unwrap(), "1004XYZ92201Y9505PROMO");
+        assert!(iso_msg.set_tag("48", "951", "X").is_err());
+        assert!(iso_msg.set_tag("48", "95", "X".repeat(100).as_str()).is_err());
+
+        let data = iso_msg.assemble().unwrap();
+        let parsed = spec.parse_strict(&data).unwrap();
+        assert_eq!(parsed.get_tag("48", "22").unwrap(), "Y");
+        assert_eq!(parsed.tag_values("48").unwrap(), vec![("10".to_string(), "XYZ9".to_string()),
+                                                         ("22".to_string(), "Y".to_string()),
+                                                         ("95".to_string(), "PROMO".to_string())]);
+
+        // the length of the last element runs past the field data
+        let mut bad_data = data.clone();
+        let n = bad_data.len();
+        bad_data[n - 6] = b'6';
+        assert!(matches!(spec.parse(&bad_data), Err(ParseError::InvalidEncoding { .. })));
+    }
+
+    #[test]
+    fn test_assemble_pads_fixed_fields() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(4, "29").unwrap();
+        iso_msg.set_on(38, "APP").unwrap();
+
+        let parsed = spec.parse_strict(&iso_msg.assemble().unwrap()).unwrap();
+        assert_eq!(parsed.bmp_child_value(4).unwrap(), "000000000029");
+        assert_eq!(parsed.bmp_child_value(38).unwrap(), "APP   ");
+
+        iso_msg.set_on(4, "1000000000000").unwrap();
+        assert!(matches!(iso_msg.assemble(), Err(IsoError::Assemble(ParseError::FieldTooLong { .. }))));
+    }
+
+    #[test]
+    fn test_content_checks() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        assert!(matches!(iso_msg.set_on(4, "12A"), Err(IsoError::InvalidContent { error: ContentError::InvalidCharacters(ContentType::Numeric), .. })));
+        assert!(matches!(iso_msg.set("pan", "45679098"), Err(IsoError::InvalidContent { error: ContentError::TooShort { len: 8, min: 12 }, .. })));
+        assert!(!iso_msg.bmp.is_on(2) && !iso_msg.bmp.is_on(4));
+
+        // the values of BINARY and BCD fields should be hex (even in a lenient spec)
+        assert!(matches!(iso_msg.set_on(52, "01020304050607ZZ"), Err(IsoError::InvalidContent { error: ContentError::InvalidHex, .. })));
+        assert!(matches!(iso_msg.set_on(52, "010"), Err(IsoError::InvalidContent { error: ContentError::InvalidHex, .. })));
+        assert!(matches!(iso_msg.set_on(55, "9G"), Err(IsoError::InvalidContent { error: ContentError::InvalidHex, .. })));
+        assert!(matches!(iso_msg.set_on(55, "9F2605"), Err(IsoError::InvalidContent { error: ContentError::InvalidTlv { .. }, .. })));
+        let bcd_spec = SpecBuilder::new("BcdSpec").lenient(true)
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("0100", |m| m.fixed("message_type", 4, ASCII).bitmap("bitmap", BINARY, |b| b.fixed(3, "proc_code", 3, BCD)))
+            .build().unwrap();
+        let mut bcd_msg = new_msg(&bcd_spec, bcd_spec.get_message_from_header("0100").unwrap());
+        assert!(matches!(bcd_msg.set_on(3, "12X"), Err(IsoError::InvalidContent { error: ContentError::InvalidHex, .. })));
+        bcd_msg.set_on(3, "123").unwrap();
+        assert_eq!(bcd_msg.bmp_child_value(3).unwrap(), "0123");
+
+        // a lenient spec only logs the violations, but they are still caught when parsed by the strict spec
+        let lenient_spec = Spec::from_yaml_str(&SAMPLE_SPEC.replacen("name: SampleSpec", "name: SampleSpec\nlenient: true", 1)).unwrap();
+        let mut iso_msg = new_msg(&lenient_spec, lenient_spec.get_message_from_header("1100").unwrap());
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(4, "29").unwrap();
+        iso_msg.set_on(2, "45679098").unwrap();
+        let data = iso_msg.assemble().unwrap();
+
+        assert_eq!(lenient_spec.parse(&data).unwrap().bmp_child_value(2).unwrap(), "45679098");
+        match spec.parse(&data) {
+            Err(ParseError::InvalidContent { field, offset, error }) => {
+                assert_eq!((field.as_str(), offset), ("pan", 12));
+                assert_eq!(error, ContentError::TooShort { len: 8, min: 12 });
+            }
+            other => panic!("unexpected result - {:?}", other.map(|m| m.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_response() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+
+        let mut req = new_msg(&spec, spec.get_message_from_header("1100").unwrap());
+        let stan = req.bmp_child_value(11).unwrap();
+        assert_eq!(stan.len(), 6);
+        assert_eq!(req.bmp_child_value(7).unwrap().len(), 10);
+        assert!(req.bmp_child_value(37).unwrap().ends_with(stan.as_str()));
+        let next_stan = new_msg(&spec, spec.get_message_from_header("1100").unwrap()).bmp_child_value(11).unwrap();
+        assert_eq!(next_stan.parse::<u32>().unwrap(), stan.parse::<u32>().unwrap() + 1);
+
+        req.set("message_type", "1100").unwrap();
+        req.set_on(2, "4567909845671235").unwrap();
+        req.set_on(4, "000000000029").unwrap();
+        let req = spec.parse(&req.assemble().unwrap()).unwrap();
+
+        let mut resp = req.response().unwrap();
+        assert_eq!(resp.get("message_type").unwrap(), "1110");
+        for pos in [2, 4, 11, 37] {
+            assert_eq!(resp.bmp_child_value(pos).unwrap(), req.bmp_child_value(pos).unwrap());
+        }
+        assert!(resp.bmp.is_on(7));
+        assert!(!resp.bmp.is_on(3));
+
+        resp.set_on(39, "000").unwrap();
+        assert!(resp.validate_response(&req).is_ok());
+        assert!(matches!(resp.response(), Err(IsoError::Other(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_spec_formats() {
+        let spec = Spec::from_yaml_str(SAMPLE_SPEC).unwrap();
+        let mut iso_msg = new_1100(&spec);
+        iso_msg.set("message_type", "1100").unwrap();
+        iso_msg.set_on(2, "4567909845671235").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+        iso_msg.set_on(19, "840").unwrap();
+        let data = iso_msg.assemble().unwrap();
+
+        for format in [SpecFormat::Yaml, SpecFormat::Json, SpecFormat::Toml] {
+            let src = spec.export(format).unwrap();
+            assert_eq!(SpecFormat::detect(&src), format);
+
+            let exported = Spec::from_str_as(&src, format).unwrap();
+            assert_eq!(exported.name(), "SampleSpec");
+            assert_eq!(exported.export(format).unwrap(), src);
+            let parsed = exported.parse(&data).unwrap();
+            assert_eq!(parsed.assemble().unwrap(), data);
+            assert_eq!(parsed.bmp_child_value(19).unwrap(), "840");
+        }
+
+        assert_eq!(SpecFormat::from_path(std::path::Path::new("specs/spec.yml")), Some(SpecFormat::Yaml));
+        assert_eq!(SpecFormat::from_path(std::path::Path::new("spec.JSON")), Some(SpecFormat::Json));
+        assert_eq!(SpecFormat::from_path(std::path::Path::new("spec.toml")), Some(SpecFormat::Toml));
+        assert_eq!(SpecFormat::from_path(std::path::Path::new("spec.txt")), None);
+        assert_eq!(SpecFormat::detect("# a spec\nname = \"Spec1\""), SpecFormat::Toml);
+        assert_eq!(SpecFormat::detect("name: Spec1\nid: 1"), SpecFormat::Yaml);
+
+        match Spec::from_str_as("{\"name\": \"Spec1\",\n\"id\": }", SpecFormat::Json) {
+            Err(e) => assert_eq!(e.diagnostics[0].line, Some(2)),
+            Ok(_) => panic!("expected a SpecError")
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! This module contains a importer of jPOS GenericPackager XML definitions. Each isofield is translated into a
+//! fixed, variable or bitmapped field as per its class (like IFA_LLNUM or IFB_BITMAP), field 0 (the message type)
+//! becomes the header of the spec and the fields that follow the bitmap (field 1) the positions of the bitmap
+//!
+//! ```
+//! use iso8583_rs::iso8583::jpos::import_packager;
+//!
+//! let xml = r#"<isopackager>
+//!   <isofield id="0" length="4" name="MESSAGE TYPE INDICATOR" class="org.jpos.iso.IFA_NUMERIC"/>
+//!   <isofield id="1" length="16" name="BIT MAP" class="org.jpos.iso.IFB_BITMAP"/>
+//!   <isofield id="2" length="19" name="PAN - PRIMARY ACCOUNT NUMBER" class="org.jpos.iso.IFA_LLNUM"/>
+//!   <isofield id="4" length="12" name="AMOUNT, TRANSACTION" class="org.jpos.iso.IFA_AMOUNT"/>
+//! </isopackager>"#;
+//!
+//! let import = import_packager(xml, "Partner", &["0100", "0110"]).unwrap();
+//! assert!(import.spec.get_message_from_header("0100").is_ok());
+//! assert_eq!(import.untranslated[0].to_string(), "field 4 - class org.jpos.iso.IFA_AMOUNT cannot be translated");
+//! ```
+use std::fmt;
+
+use roxmltree::{Document, Node};
+
+use crate::iso8583::builder::new_field;
+use crate::iso8583::field::{BcdPadding, ContentType, Encoding};
+use crate::iso8583::iso_spec::{Spec, SpecError};
+use crate::iso8583::yaml_de::{YField, YMessageSegment, YSpec};
+
+/// The name of the bitmap (field 1) of the packager
+const BITMAP: &str = "bitmap";
+
+/// This struct represents a field of a packager whose class could not be translated, the field is
+/// left out of the spec
+#[derive(Debug, PartialEq)]
+pub struct Untranslated {
+    /// The id of the field (like 127.3 for a subfield)
+    pub field: String,
+    /// The class of the field
+    pub class: String,
+}
+
+impl fmt::Display for Untranslated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "field {} - class {} cannot be translated", self.field, self.class)
+    }
+}
+
+/// This struct represents a spec imported from a packager along with the fields that could not be translated
+pub struct PackagerImport {
+    pub spec: Spec,
+    pub untranslated: Vec<Untranslated>,
+}
+
+/// This enum represents the kind of data of a field class - the part after the length (like NUM in IFA_LLNUM)
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Numeric,
+    Char,
+    Binary,
+    Bitmap,
+}
+
+/// Returns the field for a isofield of class with the given id, name and (max) length or None if the class
+/// cannot be translated. pad is the pad attribute of the isofield, which left pads a odd number of BCD digits
+fn field_of(class: &str, id: u32, name: &str, length: u32, pad: bool) -> Option<YField> {
+    let short = class.rsplit('.').next().unwrap_or(class);
+    let (prefix, rest) = short.split_once('_')?;
+    let len_digits = rest.chars().take_while(|c| *c == 'L').count();
+    let rest = &rest[len_digits..];
+
+    // a H after the L's is a binary length indicator (like IFB_LLHCHAR)
+    let (binary_len, kind) = match rest.strip_prefix('H') {
+        Some(kind) if len_digits > 0 => (true, kind),
+        _ => (false, rest)
+    };
+    let kind = match kind {
+        "NUM" | "NUMERIC" => Kind::Numeric,
+        "CHAR" => Kind::Char,
+        "BINARY" => Kind::Binary,
+        "BITMAP" => Kind::Bitmap,
+        _ => return None
+    };
+
+    // the encoding of the data and of the length indicator of each prefix, fixed IFA_BINARY and IFE_BINARY
+    // fields hold their data as hex characters which has no equivalent
+    let (encoding, len_encoding) = match (prefix, kind) {
+        ("IF", Kind::Char) => (Encoding::ASCII, Encoding::ASCII),
+        ("IFA", Kind::Binary) | ("IFE", Kind::Binary) if len_digits == 0 => return None,
+        ("IFA", Kind::Binary) => (Encoding::BINARY, Encoding::ASCII),
+        ("IFE", Kind::Binary) => (Encoding::BINARY, Encoding::EBCDIC),
+        ("IFA", _) => (Encoding::ASCII, Encoding::ASCII),
+        ("IFE", _) => (Encoding::EBCDIC, Encoding::EBCDIC),
+        ("IFB", Kind::Numeric) => (Encoding::BCD, Encoding::BCD),
+        ("IFB", Kind::Char) => (Encoding::ASCII, Encoding::BCD),
+        ("IFB", _) => (Encoding::BINARY, Encoding::BCD),
+        ("IFEB", Kind::Numeric) => (Encoding::BCD, Encoding::EBCDIC),
+        _ => return None
+    };
+    let len_encoding = match (binary_len, prefix) {
+        (false, _) => len_encoding,
+        (true, "IFB") => Encoding::BINARY,
+        (true, _) => return None
+    };
+
+    let mut field = match (kind, len_digits) {
+        (Kind::Bitmap, 0) => return Some(new_field(name, id as usize, "Bitmapped", 0, None, encoding, None)),
+        (Kind::Bitmap, _) => return None,
+        (_, 0) => new_field(name, id as usize, "Fixed", length, None, encoding, Some(id)),
+        (_, digits) => {
+            // a BCD or binary length indicator holds 2 digits per byte
+            let len = match len_encoding {
+                Encoding::BCD | Encoding::BINARY => (digits as u32).div_ceil(2),
+                _ => digits as u32
+            };
+            let mut field = new_field(name, id as usize, "Variable", len, Some(len_encoding), encoding, Some(id));
+            if kind != Kind::Binary {
+                field.max_len = Some(length);
+            }
+            field
+        }
+    };
+    if matches!(encoding, Encoding::BCD) {
+        field.bcd_padding = Some(if pad { BcdPadding::Left0 } else { BcdPadding::Right0 });
+    }
+    if kind == Kind::Numeric {
+        field.content = Some(ContentType::Numeric);
+    }
+    Some(field)
+}
+
+/// Returns name as a field name (like pan_primary_account_number for "PAN - PRIMARY ACCOUNT NUMBER"), fields
+/// without a usable (or with a taken) name are named after their id (like field_48_3)
+fn field_name(name: &str, path: &str, taken: &mut Vec<String>) -> String {
+    let name = name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("_");
+    let name = if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) || taken.contains(&name) {
+        format!("field_{}", path.replace('.', "_"))
+    } else {
+        name
+    };
+    taken.push(name.clone());
+    name
+}
+
+/// Returns the value of the attribute attr of node as a number (or default, if it is not present)
+fn num_attr(doc: &Document, node: &Node, attr: &str, default: Option<u32>) -> Result<u32, SpecError> {
+    match (node.attribute(attr), default) {
+        (Some(v), _) => v.trim().parse::<u32>().ok(),
+        (None, default) => default
+    }.ok_or_else(|| SpecError::new(node.tag_name().name(), format!("{} should be a number", attr).as_str(), Some(line_of(doc, node))))
+}
+
+fn line_of(doc: &Document, node: &Node) -> usize {
+    doc.text_pos_at(node.range().start).row as usize
+}
+
+/// Returns the fields defined by the isofield and isofieldpackager elements of node, path is the id of the
+/// field node belongs to (empty for the packager). Fields whose class cannot be translated are added to untranslated
+fn fields_of(doc: &Document, node: &Node, path: &str, taken: &mut Vec<String>, untranslated: &mut Vec<Untranslated>) -> Result<Vec<YField>, SpecError> {
+    let mut fields = vec![];
+    for child in node.children().filter(|c| matches!(c.tag_name().name(), "isofield" | "isofieldpackager")) {
+        let id = num_attr(doc, &child, "id", None)?;
+        let length = num_attr(doc, &child, "length", Some(0))?;
+        let field_path = if path.is_empty() { id.to_string() } else { format!("{}.{}", path, id) };
+        let class = match child.attribute("class") {
+            Some(class) => class,
+            None => return Err(SpecError::new(&field_path, "class is required", Some(line_of(doc, &child))))
+        };
+        let name = field_name(child.attribute("name").unwrap_or_default(), &field_path, taken);
+
+        match field_of(class, id, &name, length, child.attribute("pad") == Some("true")) {
+            Some(mut field) => {
+                // the bitmap of a segment is looked up by name
+                if path.is_empty() && field.field_type == "Bitmapped" {
+                    field.name = BITMAP.to_string();
+                }
+                if child.tag_name().name() == "isofieldpackager" {
+                    // the subfields are defined by a nested isopackager
+                    let packager = child.children().find(|c| c.has_tag_name("isopackager")).unwrap_or(child);
+                    field.children = Some(layout(fields_of(doc, &packager, &field_path, taken, untranslated)?, true));
+                }
+                fields.push(field);
+            }
+            None => untranslated.push(Untranslated { field: field_path, class: class.to_string() })
+        }
+    }
+    Ok(fields)
+}
+
+/// Returns fields with the fields that follow a bitmap moved into the bitmap (as its positions), the fields
+/// before the bitmap keep their position only if positioned (they are subfields)
+fn layout(fields: Vec<YField>, positioned: bool) -> Vec<YField> {
+    let mut laid_out: Vec<YField> = vec![];
+    for mut f in fields {
+        match laid_out.last_mut() {
+            Some(bmp) if bmp.field_type == "Bitmapped" => bmp.children.get_or_insert_with(Vec::new).push(f),
+            _ => {
+                if !positioned && f.field_type != "Bitmapped" {
+                    f.position = None;
+                }
+                laid_out.push(f);
+            }
+        }
+    }
+    laid_out
+}
+
+/// Imports a jPOS GenericPackager XML definition as a spec with the given name, the spec has a single message
+/// segment (selected by selector, like ["*"] for any message) with all the fields of the packager. The fields
+/// whose class cannot be translated (like IFA_AMOUNT) are left out and returned along with the spec
+pub fn import_packager(xml: &str, name: &str, selector: &[&str]) -> Result<PackagerImport, SpecError> {
+    let doc = Document::parse(xml).map_err(|e| SpecError::new("", e.to_string().as_str(), Some(e.pos().row as usize)))?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "isopackager" {
+        return Err(SpecError::new(root.tag_name().name(), "not a GenericPackager definition (isopackager)", Some(line_of(&doc, &root))));
+    }
+
+    let mut untranslated = vec![];
+    let fields = layout(fields_of(&doc, &root, "", &mut vec![BITMAP.to_string()], &mut untranslated)?, false);
+    let header = match fields.first() {
+        Some(f) if f.id == 0 && f.field_type == "Fixed" => f.clone(),
+        _ => return Err(SpecError::new("0", "the packager should define a fixed message type (field 0)", None))
+    };
+
+    let spec = YSpec {
+        name: name.to_string(),
+        id: 1,
+        messages: vec![YMessageSegment {
+            name: name.to_string(),
+            id: 1,
+            selector: selector.iter().map(|s| s.to_string()).collect(),
+            fields,
+        }],
+        header_fields: vec![header],
+        lenient: None,
+    }.into_spec(None)?;
+    Ok(PackagerImport { spec, untranslated })
+}
+
+/// Imports a jPOS GenericPackager XML definition from a file (see import_packager)
+pub fn import_packager_file(file: &str, name: &str, selector: &[&str]) -> Result<PackagerImport, SpecError> {
+    match std::fs::read_to_string(file) {
+        Ok(xml) => import_packager(&xml, name, selector),
+        Err(e) => Err(SpecError::new(file, e.to_string().as_str(), None))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::iso_spec::{new_msg, SpecFormat};
+    use crate::iso8583::jpos::{import_packager, Untranslated};
+
+    const PACKAGER: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<!DOCTYPE isopackager SYSTEM "genericpackager.dtd">
+<isopackager>
+  <isofield id="0" length="4" name="MESSAGE TYPE INDICATOR" class="org.jpos.iso.IFA_NUMERIC"/>
+  <isofield id="1" length="16" name="BIT MAP" class="org.jpos.iso.IFB_BITMAP"/>
+  <isofield id="2" length="19" name="PAN - PRIMARY ACCOUNT NUMBER" pad="true" class="org.jpos.iso.IFB_LLNUM"/>
+  <isofield id="3" length="6" name="PROCESSING CODE" class="org.jpos.iso.IFA_NUMERIC"/>
+  <isofield id="4" length="12" name="AMOUNT, TRANSACTION" class="org.jpos.iso.IFB_NUMERIC"/>
+  <isofield id="28" length="9" name="AMOUNT, TRANSACTION FEE" class="org.jpos.iso.IFA_AMOUNT"/>
+  <isofield id="41" length="8" name="CARD ACCEPTOR TERMINAL IDENTIFICACION" class="org.jpos.iso.IF_CHAR"/>
+  <isofield id="43" length="40" name="CARD ACCEPTOR NAME/LOCATION" class="org.jpos.iso.IFE_CHAR"/>
+  <isofield id="52" length="8" name="PIN DATA" class="org.jpos.iso.IFB_BINARY"/>
+  <isofield id="55" length="255" name="ICC DATA" class="org.jpos.iso.IFB_LLLHBINARY"/>
+  <isofield id="62" length="999" name="RESERVED PRIVATE" class="org.jpos.iso.IFEB_LLLNUM"/>
+  <isofieldpackager id="127" length="999" name="RESERVED PRIVATE" class="org.jpos.iso.IFA_LLLCHAR"
+                    packager="org.jpos.iso.packager.GenericSubFieldPackager">
+    <isopackager>
+      <isofield id="0" length="8" name="BITMAP" class="org.jpos.iso.IFB_BITMAP"/>
+      <isofield id="2" length="32" name="SWITCH KEY" class="org.jpos.iso.IFA_LLCHAR"/>
+      <isofield id="3" length="48" name="ROUTING INFORMATION" class="org.jpos.iso.IFA_BINARY"/>
+    </isopackager>
+  </isofieldpackager>
+</isopackager>"#;
+
+    #[test]
+    fn test_import_packager() {
+        let import = import_packager(PACKAGER, "Partner", &["0200", "0210"]).unwrap();
+        assert_eq!(import.untranslated, vec![
+            Untranslated { field: "28".to_string(), class: "org.jpos.iso.IFA_AMOUNT".to_string() },
+            Untranslated { field: "127.3".to_string(), class: "org.jpos.iso.IFA_BINARY".to_string() },
+        ]);
+
+        let spec = &import.spec;
+        let yaml = spec.export(SpecFormat::Yaml).unwrap();
+        assert!(yaml.contains("name: pan_primary_account_number"));
+        assert!(yaml.contains("name: field_127"));
+        assert!(yaml.contains("name: field_127_0"));
+
+        let mut iso_msg = new_msg(spec, spec.get_message_from_header("0200").unwrap());
+        iso_msg.set("message_type_indicator", "0200").unwrap();
+        iso_msg.set_on(2, "4567909845671").unwrap();
+        iso_msg.set_on(3, "4000").unwrap();
+        iso_msg.set_on(4, "29").unwrap();
+        iso_msg.set_on(41, "TERM01").unwrap();
+        iso_msg.set_on(43, "ACME").unwrap();
+        iso_msg.set_on(52, "0102030405060708").unwrap();
+        iso_msg.set_on(55, "9F2701").unwrap();
+        iso_msg.set_on(62, "123").unwrap();
+        iso_msg.set("127.2", "SWITCHKEY1").unwrap();
+        assert!(iso_msg.set_on(3, "40000A").is_err());
+
+        let data = iso_msg.assemble().unwrap();
+        // the pan (LLNUM with a BCD length) follows the secondary bitmap and is left padded with a 0 nibble
+        assert_eq!(&data[20..28], &[0x13, 0x04, 0x56, 0x79, 0x09, 0x84, 0x56, 0x71]);
+        // a binary length indicator (LLLH) is 2 bytes
+        assert!(data.windows(5).any(|w| w == [0x00, 0x03, 0x9F, 0x27, 0x01]));
+
+        let parsed = spec.parse(&data).unwrap();
+        assert_eq!(parsed.bmp_child_value(3).unwrap(), "004000");
+        assert_eq!(parsed.bmp_child_value(4).unwrap(), "000000000029");
+        assert_eq!(parsed.bmp_child_value(41).unwrap(), "TERM01  ");
+        assert_eq!(parsed.bmp_child_value(43).unwrap().trim_end(), "ACME");
+        assert_eq!(parsed.bmp_child_value(62).unwrap(), "123");
+        assert_eq!(parsed.get("127.2").unwrap(), "SWITCHKEY1");
+
+        // the message type of the response is set through the header field of the spec
+        assert_eq!(parsed.response().unwrap().get("message_type_indicator").unwrap(), "0210");
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let err = |xml: &str| import_packager(xml, "Partner", &["0200"]).err().unwrap().diagnostics.remove(0);
+
+        assert_eq!(err("<genericpackager/>").message, "not a GenericPackager definition (isopackager)");
+        assert_eq!(err("<isopackager>\n<isofield id=\"x\" class=\"org.jpos.iso.IF_CHAR\"/></isopackager>").line, Some(2));
+        assert_eq!(err("<isopackager><isofield id=\"2\" length=\"19\"/></isopackager>").message, "class is required");
+        assert_eq!(err("<isopackager><isofield id=\"1\" length=\"16\" class=\"org.jpos.iso.IFB_BITMAP\"/></isopackager>").message,
+                   "the packager should define a fixed message type (field 0)");
+        assert!(err("<isopackager><isofield>\n</isopackager>").line.is_some());
+    }
+}
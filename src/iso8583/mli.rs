@@ -1,7 +1,7 @@
 //! This module contains implementation of various MLI types associated with a ISO message
 use crate::iso8583::IsoError;
 use byteorder::{WriteBytesExt, ReadBytesExt};
-use std::io::{Read, ErrorKind, Error};
+use std::io::Read;
 
 
 pub enum MLIType {
@@ -30,17 +30,6 @@ pub struct MLI4E {}
 /// This struct represents an MLI of 4I (i.e 4 bytes of length indicator inclusive of its own length)
 pub struct MLI4I {}
 
-/// convert a std::io::Error into an IsoError
-fn convert_err(e: &Error) -> IsoError {
-    match e.kind() {
-        ErrorKind::ConnectionReset | ErrorKind::UnexpectedEof => {
-            IsoError { msg: format!("connection closed. cause: {:?}", e.kind()) }
-        }
-        _ => {
-            IsoError { msg: format!("{:?}: {}", e.kind(), e.to_string()) }
-        }
-    }
-}
 
 impl MLI for MLI2E {
     fn parse(&self, in_buf: &mut dyn Read) -> Result<u32, IsoError> {
@@ -49,7 +38,7 @@ impl MLI for MLI2E {
                 Ok(n as u32)
             }
             Err(e) => {
-                Err(convert_err(&e))
+                Err(e.into())
             }
         }
     }
@@ -67,7 +56,7 @@ impl MLI for MLI4E {
         match in_buf.read_u32::<byteorder::BigEndian>() {
             Ok(n) => Ok(n),
             Err(e) => {
-                Err(convert_err(&e))
+                Err(e.into())
             }
         }
     }
@@ -85,7 +74,7 @@ impl MLI for MLI2I {
         match in_buf.read_u16::<byteorder::BigEndian>() {
             Ok(n) => Ok((n - 2) as u32),
             Err(e) => {
-                Err(convert_err(&e))
+                Err(e.into())
             }
         }
     }
@@ -102,7 +91,7 @@ impl MLI for MLI4I {
         match in_buf.read_u32::<byteorder::BigEndian>() {
             Ok(n) => Ok(n - 4),
             Err(e) => {
-                Err(convert_err(&e))
+                Err(e.into())
             }
         }
     }
@@ -6,11 +6,140 @@ pub mod iso_spec;
 pub mod server;
 mod test;
 mod yaml_de;
+mod spec_validator;
 pub mod mli;
 pub mod config;
+pub mod registry;
+pub mod builder;
+pub mod trace;
+pub mod tlv;
+pub mod rules;
+pub mod defaults;
+pub mod jpos;
+pub mod selector;
 
-/// IsoError represents a generic error throughout this and dependent sub-modules
+use std::fmt;
+
+use crate::crypto::mac::MacError;
+use crate::crypto::pin::PinError;
+use crate::iso8583::field::{ContentError, ParseError};
+use crate::iso8583::iso_spec::SpecError;
+use crate::iso8583::server::IsoServerError;
+
+/// IsoError represents a error throughout this and dependent sub-modules. Errors from the lower
+/// layers (parsing, specs, crypto, io) are wrapped and available via std::error::Error::source
 #[derive(Debug)]
-pub struct IsoError {
-    pub msg: String,
+pub enum IsoError {
+    /// The message could not be parsed
+    Parse(ParseError),
+    /// The message could not be assembled
+    Assemble(ParseError),
+    /// The spec definition is not valid
+    Spec(SpecError),
+    /// No spec with the given name (or id) is registered
+    SpecNotFound(String),
+    /// The spec conflicts (by name or id) with a spec that is already registered
+    SpecConflict { name: String, id: u32 },
+    /// No message segment is defined with the given name or for the given header value
+    MessageNotFound(String),
+    /// The field (name or position) is not defined in the message segment
+    FieldNotFound(String),
+    /// The field is defined but has no value in the message
+    FieldNotSet(String),
+    /// The value does not conform to the content rules of the field
+    InvalidContent { field: String, error: ContentError },
+    /// The bitmap data is not 8, 16 or 24 bytes long
+    InvalidBitmap(usize),
+    /// A configuration required by the operation is missing
+    MissingConfig(String),
+    /// The PIN block could not be generated or verified
+    Pin(PinError),
+    /// The MAC could not be generated or verified
+    Mac(MacError),
+    /// The server could not be created
+    Server(IsoServerError),
+    /// The peer closed the connection
+    ConnectionClosed,
+    /// Any other IO error
+    Io(std::io::Error),
+    /// Any other error (for example, raised by a MsgProcessor)
+    Other(String),
+}
+
+impl fmt::Display for IsoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsoError::Parse(e) => write!(f, "{}", e),
+            IsoError::Assemble(e) => write!(f, "failed to assemble message: {}", e),
+            IsoError::Spec(e) => write!(f, "{}", e),
+            IsoError::SpecNotFound(name) => write!(f, "spec {} not found", name),
+            IsoError::SpecConflict { name, id } => write!(f, "spec {} (id: {}) conflicts with a registered spec", name, id),
+            IsoError::MessageNotFound(name) => write!(f, "message not found - {}", name),
+            IsoError::FieldNotFound(name) => write!(f, "no such field - {}", name),
+            IsoError::FieldNotSet(name) => write!(f, "no value for field - {}", name),
+            IsoError::InvalidContent { field, error } => write!(f, "invalid value for field {} - {}", field, error),
+            IsoError::InvalidBitmap(len) => write!(f, "invalid bitmap length - {}", len),
+            IsoError::MissingConfig(msg) => write!(f, "missing configuration - {}", msg),
+            IsoError::Pin(e) => write!(f, "{}", e),
+            IsoError::Mac(e) => write!(f, "{}", e),
+            IsoError::Server(e) => write!(f, "{}", e),
+            IsoError::ConnectionClosed => f.write_str("connection closed"),
+            IsoError::Io(e) => write!(f, "{:?}: {}", e.kind(), e),
+            IsoError::Other(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for IsoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IsoError::Parse(e) | IsoError::Assemble(e) => Some(e),
+            IsoError::Spec(e) => Some(e),
+            IsoError::Pin(e) => Some(e),
+            IsoError::Mac(e) => Some(e),
+            IsoError::Server(e) => Some(e),
+            IsoError::InvalidContent { error, .. } => Some(error),
+            IsoError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<ParseError> for IsoError {
+    fn from(e: ParseError) -> Self {
+        IsoError::Parse(e)
+    }
+}
+
+impl From<SpecError> for IsoError {
+    fn from(e: SpecError) -> Self {
+        IsoError::Spec(e)
+    }
+}
+
+impl From<PinError> for IsoError {
+    fn from(e: PinError) -> Self {
+        IsoError::Pin(e)
+    }
+}
+
+impl From<MacError> for IsoError {
+    fn from(e: MacError) -> Self {
+        IsoError::Mac(e)
+    }
+}
+
+impl From<IsoServerError> for IsoError {
+    fn from(e: IsoServerError) -> Self {
+        IsoError::Server(e)
+    }
+}
+
+impl From<std::io::Error> for IsoError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::UnexpectedEof => IsoError::ConnectionClosed,
+            _ => IsoError::Io(e)
+        }
+    }
 }
@@ -0,0 +1,221 @@
+//! This module contains the implementation of a registry of specifications, so that multiple specs
+//! can be loaded (from files, directories or strings) and looked up by name or id
+use std::sync::{Arc, RwLock};
+
+use crate::iso8583::iso_spec::{Spec, SpecFormat};
+use crate::iso8583::IsoError;
+
+// The default (process wide) registry. If the SPEC_FILE env variable is defined, the spec
+// in that file is loaded into the registry when it is first accessed
+lazy_static! {
+static ref DEFAULT_REGISTRY: SpecRegistry = {
+    let registry = SpecRegistry::new();
+
+    if let Some(v) = std::env::var_os("SPEC_FILE") {
+        match v.to_str() {
+            Some(spec_file) => {
+                debug!("spec-file: {}", spec_file);
+                if let Err(e) = registry.load_file(spec_file) {
+                    error!("failed to load spec from SPEC_FILE - {}", e);
+                }
+            }
+            None => error!("SPEC_FILE env variable is not valid unicode")
+        }
+    }
+
+    registry
+};
+}
+
+/// This struct represents a collection of specs that can be looked up by name or id
+pub struct SpecRegistry {
+    specs: RwLock<Vec<Arc<Spec>>>,
+}
+
+impl SpecRegistry {
+    /// Creates and returns a new empty SpecRegistry
+    pub fn new() -> SpecRegistry {
+        SpecRegistry {
+            specs: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Adds a spec to the registry and returns it or a IsoError if a spec with the same name or
+    /// id is already registered
+    pub fn register(&self, spec: Spec) -> Result<Arc<Spec>, IsoError> {
+        let mut specs = self.specs.write().unwrap();
+
+        if specs.iter().any(|s| s.name() == spec.name() || s.id() == spec.id()) {
+            return Err(IsoError::SpecConflict { name: spec.name().to_string(), id: spec.id() });
+        }
+
+        let spec = Arc::new(spec);
+        specs.push(spec.clone());
+        Ok(spec)
+    }
+
+    /// Adds a spec to the registry, replacing any registered spec with the same name. The replaced
+    /// spec (if any) is returned, messages that still refer to it remain valid. Returns a IsoError if
+    /// a spec with a different name has the same id
+    pub fn replace(&self, spec: Spec) -> Result<Option<Arc<Spec>>, IsoError> {
+        let mut specs = self.specs.write().unwrap();
+
+        if specs.iter().any(|s| s.name() != spec.name() && s.id() == spec.id()) {
+            return Err(IsoError::SpecConflict { name: spec.name().to_string(), id: spec.id() });
+        }
+
+        let prev = specs.iter().position(|s| s.name() == spec.name()).map(|i| specs.remove(i));
+        specs.push(Arc::new(spec));
+        Ok(prev)
+    }
+
+    /// Removes a spec given its name from the registry and returns it
+    pub fn remove(&self, name: &str) -> Option<Arc<Spec>> {
+        let mut specs = self.specs.write().unwrap();
+        specs.iter().position(|s| s.name() == name).map(|i| specs.remove(i))
+    }
+
+    /// Loads a spec from a YAML, JSON or TOML file (see Spec::from_file) and adds it to the registry
+    pub fn load_file(&self, spec_file: &str) -> Result<Arc<Spec>, IsoError> {
+        self.register(Spec::from_file(spec_file)?)
+    }
+
+    /// Loads a spec from a YAML, JSON or TOML string (the format is determined by SpecFormat::detect) and adds
+    /// it to the registry
+    pub fn load_str(&self, src: &str) -> Result<Arc<Spec>, IsoError> {
+        self.register(Spec::from_str_as(src, SpecFormat::detect(src))?)
+    }
+
+    /// Loads all specs (files with a .yaml, .yml, .json or .toml extension) from a directory and adds them to the registry.
+    /// Files are loaded in the order of their names and loading stops at the first failure
+    pub fn load_dir(&self, dir: &str) -> Result<Vec<Arc<Spec>>, IsoError> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => return Err(IsoError::Io(e))
+        };
+
+        let mut files = entries.filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .filter(|p| SpecFormat::from_path(p).is_some())
+            .collect::<Vec<_>>();
+        files.sort();
+
+        let mut loaded = vec![];
+        for f in files {
+            match f.to_str() {
+                Some(spec_file) => loaded.push(self.load_file(spec_file)?),
+                None => return Err(IsoError::Other(format!("invalid spec file name - {:?}", f)))
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Returns a spec given its name or a IsoError if no such spec is registered
+    pub fn by_name(&self, name: &str) -> Result<Arc<Spec>, IsoError> {
+        match self.specs.read().unwrap().iter().find(|s| s.name() == name) {
+            Some(s) => Ok(s.clone()),
+            None => Err(IsoError::SpecNotFound(name.to_string()))
+        }
+    }
+
+    /// Returns a spec given its id or a IsoError if no such spec is registered
+    pub fn by_id(&self, id: u32) -> Result<Arc<Spec>, IsoError> {
+        match self.specs.read().unwrap().iter().find(|s| s.id() == id) {
+            Some(s) => Ok(s.clone()),
+            None => Err(IsoError::SpecNotFound(format!("id {}", id)))
+        }
+    }
+
+    /// Returns the names of all registered specs
+    pub fn names(&self) -> Vec<String> {
+        self.specs.read().unwrap().iter().map(|s| s.name().to_string()).collect()
+    }
+}
+
+impl Default for SpecRegistry {
+    fn default() -> Self {
+        SpecRegistry::new()
+    }
+}
+
+/// Returns the default (process wide) registry
+pub fn default_registry() -> &'static SpecRegistry {
+    &DEFAULT_REGISTRY
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::iso8583::iso_spec::Spec;
+    use crate::iso8583::registry::SpecRegistry;
+    use crate::iso8583::IsoError;
+
+    const SPEC_1: &str = r#"
+name: Spec1
+id: 1
+header_fields:
+  - name: "message_type"
+    id: 1
+    type: Fixed
+    len: 4
+    data_encoding: ASCII
+messages:
+  - name: "Auth"
+    id: 1
+    selector: ["1100"]
+    fields:
+      - name: "message_type"
+        id: 1
+        type: Fixed
+        len: 4
+        data_encoding: ASCII
+"#;
+
+    #[test]
+    fn test_load_and_lookup() {
+        let registry = SpecRegistry::new();
+        registry.load_str(SPEC_1).unwrap();
+        registry.load_dir("sample_spec").unwrap();
+
+        assert_eq!(registry.by_name("Spec1").unwrap().id(), 1);
+        assert_eq!(registry.by_name("SampleSpec").unwrap().id(), 3);
+        assert_eq!(registry.by_id(3).unwrap().name(), "SampleSpec");
+        assert_eq!(registry.names(), vec!["Spec1", "SampleSpec"]);
+
+        assert!(matches!(registry.by_name("Visa"), Err(IsoError::SpecNotFound(_))));
+        assert!(matches!(registry.by_id(99), Err(IsoError::SpecNotFound(_))));
+    }
+
+    #[test]
+    fn test_duplicate_and_missing() {
+        let registry = SpecRegistry::new();
+        registry.load_str(SPEC_1).unwrap();
+
+        assert!(matches!(registry.load_str(SPEC_1), Err(IsoError::SpecConflict { id: 1, .. })));
+        assert!(matches!(registry.load_file("sample_spec/missing.yaml"), Err(IsoError::Spec(_))));
+        assert!(matches!(registry.load_dir("no_such_dir"), Err(IsoError::Io(_))));
+    }
+
+    #[test]
+    fn test_replace_and_remove() {
+        let registry = SpecRegistry::new();
+        let old = registry.load_str(SPEC_1).unwrap();
+
+        let prev = registry.replace(Spec::from_yaml_str(&SPEC_1.replace("id: 1\nheader", "id: 7\nheader")).unwrap()).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&old, &prev));
+        assert_eq!(registry.by_name("Spec1").unwrap().id(), 7);
+        assert_eq!(old.id(), 1);
+
+        // a spec with another name cannot take the id of a registered spec
+        let other = Spec::from_yaml_str(&SPEC_1.replace("Spec1", "Spec2").replace("id: 1\nheader", "id: 7\nheader")).unwrap();
+        assert!(matches!(registry.replace(other), Err(IsoError::SpecConflict { id: 7, .. })));
+        assert_eq!(registry.names(), vec!["Spec1"]);
+
+        assert!(registry.remove("Spec1").is_some());
+        assert!(registry.remove("Spec1").is_none());
+        assert!(registry.by_name("Spec1").is_err());
+    }
+}
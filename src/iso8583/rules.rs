@@ -0,0 +1,184 @@
+//! This module contains the presence rules (mandatory, optional, conditional and echo) of the fields
+//! of a message segment and their validation
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::iso8583::field::Field;
+use crate::iso8583::iso_spec::IsoMsg;
+
+/// This enum represents the presence of a field in a message
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    /// The field should always be present
+    Mandatory,
+    /// The field may be present
+    Optional,
+    /// The field should be present when its condition (see FieldRule::when) is met, it may be present otherwise
+    Conditional,
+    /// The field may be present, but a response should carry the same value as the request
+    Echo,
+}
+
+/// This struct represents the presence rule of a field of a message segment
+#[derive(Clone, Debug)]
+pub struct FieldRule {
+    /// The name of the field
+    pub field: String,
+    pub presence: Presence,
+    /// The condition of a conditional field - the path of a field that should be present (like "52") or
+    /// the path of a field and the value it should have (like "message_type=1110")
+    pub when: Option<String>,
+}
+
+impl FieldRule {
+    /// Returns true if the field is required in iso_msg
+    fn is_required(&self, iso_msg: &IsoMsg) -> bool {
+        match (self.presence, &self.when) {
+            (Presence::Mandatory, _) => true,
+            (Presence::Conditional, Some(when)) => match when.split_once('=') {
+                Some((path, value)) => iso_msg.get(path).map(|v| v == value).unwrap_or(false),
+                None => iso_msg.get(when).is_ok()
+            },
+            _ => false
+        }
+    }
+}
+
+/// This enum represents a field of a message that breaks the presence rules of its segment
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    /// A mandatory (or conditional, whose condition is met) field is not present
+    Missing(String),
+    /// The field is present, but has no presence rule in a segment that defines rules
+    NotAllowed(String),
+    /// The value of a echo field differs from its value in the request
+    NotEchoed(String),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Missing(field) => write!(f, "missing field - {}", field),
+            Violation::NotAllowed(field) => write!(f, "field not allowed - {}", field),
+            Violation::NotEchoed(field) => write!(f, "field not echoed - {}", field),
+        }
+    }
+}
+
+/// Returns the fields of all bitmaps (including nested bitmaps) in fields
+fn bitmap_fields<'a>(fields: &[&'a dyn Field], out: &mut Vec<&'a dyn Field>) {
+    for f in fields {
+        if f.as_bmp().is_some() {
+            out.extend(f.children());
+        }
+        bitmap_fields(&f.children(), out);
+    }
+}
+
+/// Checks the fields of iso_msg against the rules of its segment and returns all violations found. When the
+/// message is a response to req_msg, the echo fields of the request are checked as well
+pub(in crate::iso8583) fn check_rules(iso_msg: &IsoMsg, req_msg: Option<&IsoMsg>) -> Vec<Violation> {
+    let rules = &iso_msg.msg.rules;
+    if rules.is_empty() {
+        return vec![];
+    }
+    let rules: HashMap<&str, &FieldRule> = rules.iter().map(|r| (r.field.as_str(), r)).collect();
+
+    let mut fields = vec![];
+    bitmap_fields(&iso_msg.msg.fields.iter().map(|f| f.as_ref()).collect::<Vec<_>>(), &mut fields);
+
+    let mut violations = vec![];
+    for f in fields {
+        let value = iso_msg.fd_map.get(f.name());
+        match rules.get(f.name().as_str()) {
+            Some(rule) if value.is_none() && rule.is_required(iso_msg) => violations.push(Violation::Missing(f.name().clone())),
+            Some(rule) if rule.presence == Presence::Echo => {
+                if let Some(req_value) = req_msg.and_then(|req| req.fd_map.get(f.name())) {
+                    if value != Some(req_value) {
+                        violations.push(Violation::NotEchoed(f.name().clone()));
+                    }
+                }
+            }
+            Some(_) => {}
+            None if value.is_some() => violations.push(Violation::NotAllowed(f.name().clone())),
+            None => {}
+        }
+    }
+    violations
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::builder::SpecBuilder;
+    use crate::iso8583::field::Encoding::{ASCII, BINARY};
+    use crate::iso8583::field::ParseError;
+    use crate::iso8583::iso_spec::{new_msg, Spec};
+    use crate::iso8583::rules::{Presence, Violation};
+    use crate::iso8583::IsoError;
+
+    fn build_spec(when: Option<&str>) -> Result<Spec, IsoError> {
+        Ok(SpecBuilder::new("RulesSpec")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("0100", |m| m.selector(&["0100", "0110"])
+                .fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.var(2, "pan", 2, ASCII, ASCII).presence(Presence::Echo, None)
+                    .fixed(4, "amount", 12, ASCII).presence(Presence::Mandatory, None)
+                    .fixed(39, "action_code", 3, ASCII).presence(Presence::Conditional, when)
+                    .fixed(41, "terminal_id", 8, ASCII)))
+            .build()?)
+    }
+
+    #[test]
+    fn test_presence_rules() {
+        let spec = build_spec(Some("message_type=0110")).unwrap();
+
+        let mut req = new_msg(&spec, spec.get_message_from_header("0100").unwrap());
+        req.set("message_type", "0100").unwrap();
+        req.set_on(2, "4567909845671235").unwrap();
+        req.set_on(41, "TERM0001").unwrap();
+        assert_eq!(req.validate(), Err(vec![Violation::Missing("amount".to_string()), Violation::NotAllowed("terminal_id".to_string())]));
+        assert!(matches!(req.assemble(), Err(IsoError::Assemble(ParseError::RuleViolations(v))) if v.len() == 2));
+
+        req.set_on(4, "000000000029").unwrap();
+        req.unset(41).unwrap();
+        assert_eq!(req.validate(), Ok(()));
+        let req = spec.parse(&req.assemble().unwrap()).unwrap();
+
+        // action_code is required in the response, which should also echo the pan of the request
+        let mut resp = new_msg(&spec, spec.get_message_from_header("0110").unwrap());
+        resp.set("message_type", "0110").unwrap();
+        resp.set_on(2, "4567909845671236").unwrap();
+        resp.set_on(4, "000000000029").unwrap();
+        assert_eq!(resp.validate(), Err(vec![Violation::Missing("action_code".to_string())]));
+
+        resp.set_on(39, "000").unwrap();
+        assert_eq!(resp.validate(), Ok(()));
+        assert_eq!(resp.validate_response(&req), Err(vec![Violation::NotEchoed("pan".to_string())]));
+        resp.echo_from(&req, &[2]).unwrap();
+        assert_eq!(resp.validate_response(&req), Ok(()));
+
+        // a message without a mandatory field fails to parse (amount is turned off in the bitmap)
+        let mut data = req.assemble().unwrap();
+        data[4] &= !0x10;
+        match spec.parse(&data) {
+            Err(ParseError::RuleViolations(v)) => assert_eq!(v, vec![Violation::Missing("amount".to_string())]),
+            _ => panic!("expected a RuleViolations error")
+        }
+    }
+
+    #[test]
+    fn test_invalid_conditions() {
+        match build_spec(None) {
+            Err(IsoError::Spec(e)) => assert_eq!(e.diagnostics[0].message, "a conditional field requires a condition (when)"),
+            _ => panic!("expected a SpecError")
+        }
+        match build_spec(Some("no_such_field=1")) {
+            Err(IsoError::Spec(e)) => assert_eq!(e.diagnostics[0].message, "condition no_such_field=1 refers to a undefined field"),
+            _ => panic!("expected a SpecError")
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! This module contains the selectors of message segments. A selector is a pattern the value of the header
+//! is matched against, optionally followed by conditions on fields of the message. In a pattern a 'x' (or 'X')
+//! matches any character and a trailing '*' any remaining characters, for example -
+//!
+//! * "1100" - the header 1100
+//! * "11xx" or "11*" - any header that starts with 11
+//! * "0200 3=31xxxx" - the header 0200 with a processing code (DE3) that starts with 31
+//! * "*" - any header, the default segment
+//!
+//! When more than one selector matches a message, the one with the most conditions wins and then the one
+//! with the most (non wildcard) characters in its pattern; between equal selectors the first defined wins
+use std::fmt;
+
+/// This struct represents a (parsed) selector of a message segment
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selector {
+    /// The pattern of the header value
+    header: String,
+    /// The path of a field and the pattern of its value, for each condition
+    conditions: Vec<(String, String)>,
+}
+
+impl Selector {
+    /// Returns the selector in src or a description of why it is not valid
+    pub fn parse(src: &str) -> Result<Selector, String> {
+        let mut parts = src.split_whitespace();
+        let header = match parts.next() {
+            Some(header) => header.to_string(),
+            None => return Err("selector cannot be empty".to_string())
+        };
+        check_pattern(&header)?;
+
+        let mut conditions = vec![];
+        for part in parts {
+            match part.split_once('=') {
+                Some((path, pattern)) if !path.is_empty() && !pattern.is_empty() => {
+                    check_pattern(pattern)?;
+                    conditions.push((path.to_string(), pattern.to_string()));
+                }
+                _ => return Err(format!("condition {} should be path=pattern", part))
+            }
+        }
+        Ok(Selector { header, conditions })
+    }
+
+    /// Returns true if header matches the pattern of the header of the selector
+    pub fn matches_header(&self, header: &str) -> bool {
+        matches_pattern(&self.header, header)
+    }
+
+    /// Returns true if the selector has conditions on fields of the message (beyond the header)
+    pub fn has_conditions(&self) -> bool {
+        !self.conditions.is_empty()
+    }
+
+    /// Returns true if all conditions hold, value returns the value of a field given its path
+    pub(in crate::iso8583) fn matches_fields<F>(&self, value: F) -> bool where F: Fn(&str) -> Option<String> {
+        self.conditions.iter().all(|(path, pattern)| value(path).map(|v| matches_pattern(pattern, &v)).unwrap_or(false))
+    }
+
+    /// Returns the conditions as path=pattern
+    pub(in crate::iso8583) fn conditions(&self) -> Vec<String> {
+        self.conditions.iter().map(|(path, pattern)| format!("{}={}", path, pattern)).collect()
+    }
+
+    /// Returns how specific the selector is - the number of conditions and the number of non wildcard
+    /// characters of the header pattern
+    pub(in crate::iso8583) fn specificity(&self) -> (usize, usize) {
+        (self.conditions.len(), self.header.chars().filter(|c| !is_wildcard(*c) && *c != '*').count())
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.header)?;
+        for (path, pattern) in &self.conditions {
+            write!(f, " {}={}", path, pattern)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_wildcard(c: char) -> bool {
+    c == 'x' || c == 'X'
+}
+
+/// Returns an error if a '*' is not the last character of pattern
+fn check_pattern(pattern: &str) -> Result<(), String> {
+    match pattern.find('*') {
+        Some(i) if i != pattern.len() - 1 => Err(format!("pattern {} can only end with a *", pattern)),
+        _ => Ok(())
+    }
+}
+
+/// Returns true if value matches pattern
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let (pattern, prefix) = match pattern.strip_suffix('*') {
+        Some(p) => (p, true),
+        None => (pattern, false)
+    };
+    let len = pattern.chars().count();
+    let value_len = value.chars().count();
+    if value_len < len || (!prefix && value_len != len) {
+        return false;
+    }
+    pattern.chars().zip(value.chars()).all(|(p, v)| is_wildcard(p) || p == v)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::builder::SpecBuilder;
+    use crate::iso8583::field::Encoding::{ASCII, BINARY};
+    use crate::iso8583::field::ParseError;
+    use crate::iso8583::iso_spec::{new_msg, Spec};
+    use crate::iso8583::selector::Selector;
+    use crate::iso8583::IsoError;
+
+    #[test]
+    fn test_selectors() {
+        let selector = Selector::parse("02xx  3=31*").unwrap();
+        assert_eq!(selector.to_string(), "02xx 3=31*");
+        assert!(selector.matches_header("0210"));
+        assert!(!selector.matches_header("021"));
+        assert!(!selector.matches_header("1200"));
+        assert!(selector.matches_fields(|_| Some("310000".to_string())));
+        assert!(!selector.matches_fields(|_| Some("000000".to_string())));
+        assert!(!selector.matches_fields(|_| None));
+        assert_eq!(selector.specificity(), (1, 2));
+
+        assert!(Selector::parse("*").unwrap().matches_header(""));
+        assert!(Selector::parse("11*").unwrap().matches_header("1100"));
+        assert_eq!(Selector::parse("1*1").err().unwrap(), "pattern 1*1 can only end with a *");
+        assert_eq!(Selector::parse("0200 3").err().unwrap(), "condition 3 should be path=pattern");
+        assert!(Selector::parse(" ").is_err());
+    }
+
+    fn build_spec() -> Result<Spec, IsoError> {
+        Ok(SpecBuilder::new("SelectorSpec")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("Purchase", |m| m.selector(&["02xx"])
+                .fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.fixed(3, "proc_code", 6, ASCII).fixed(4, "amount", 12, ASCII)))
+            .message("Balance Inquiry", |m| m.selector(&["02xx 3=31xxxx"])
+                .fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.fixed(3, "proc_code", 6, ASCII).fixed(54, "balance", 20, ASCII)))
+            .message("Network Management", |m| m.selector(&["0800", "0810"])
+                .fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.fixed(70, "network_code", 3, ASCII)))
+            .message("Other", |m| m.selector(&["*"])
+                .fixed("message_type", 4, ASCII))
+            .build()?)
+    }
+
+    #[test]
+    fn test_segment_selection() {
+        let spec = build_spec().unwrap();
+        assert_eq!(spec.get_message_from_header("0200").unwrap().name(), "Purchase");
+        assert_eq!(spec.get_message_from_header("0800").unwrap().name(), "Network Management");
+        assert_eq!(spec.get_message_from_header("9999").unwrap().name(), "Other");
+
+        let assemble = |seg: &str, pos: u32, val: &str| {
+            let mut iso_msg = new_msg(&spec, spec.get_message(seg).unwrap());
+            iso_msg.set("message_type", "0200").unwrap();
+            iso_msg.set_on(3, &format!("{}0000", &val[..2])).unwrap();
+            iso_msg.set_on(pos, val).unwrap();
+            iso_msg.assemble().unwrap()
+        };
+        let data = assemble("Purchase", 4, "000000000029");
+        assert_eq!(spec.get_msg_segment(&data).unwrap().name(), "Purchase");
+        let data = assemble("Balance Inquiry", 54, "31000000000000000100");
+        let iso_msg = spec.parse(&data).unwrap();
+        assert_eq!(iso_msg.msg.name(), "Balance Inquiry");
+        assert_eq!(iso_msg.bmp_child_value(54).unwrap(), "31000000000000000100");
+        // the segment of a response is selected with the fields of the request
+        assert_eq!(iso_msg.response().unwrap().msg.name(), "Balance Inquiry");
+        let data = assemble("Purchase", 4, "000000000029");
+        assert_eq!(spec.parse(&data).unwrap().response().unwrap().msg.name(), "Purchase");
+
+        // selectors that are not valid or refer to a undefined field
+        match SpecBuilder::new("SelectorSpec")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("Purchase", |m| m.selector(&["02xx 9x=1", "1*0"]).fixed("message_type", 4, ASCII))
+            .build() {
+            Err(e) => {
+                assert_eq!(e.diagnostics[0].message, "selector 1*0 is not valid - pattern 1*0 can only end with a *");
+                assert_eq!(e.diagnostics[1].message, "condition 9x=1 refers to a undefined field");
+            }
+            Ok(_) => panic!("expected a SpecError")
+        }
+
+        let spec = SpecBuilder::new("SelectorSpec")
+            .header(|h| h.fixed("message_type", 4, ASCII))
+            .message("Purchase", |m| m.selector(&["0200"]).fixed("message_type", 4, ASCII))
+            .build().unwrap();
+        assert!(matches!(spec.get_msg_segment(b"0400"), Err(ParseError::NoMatchingSegment { header }) if header == "0400"));
+    }
+
+    #[test]
+    fn test_response_header() {
+        // the header is the tpdu followed by the message type
+        let spec = SpecBuilder::new("SelectorSpec")
+            .header(|h| h.fixed("tpdu", 4, ASCII).fixed("mti", 4, ASCII))
+            .message("Request", |m| m.selector(&["xxxx0200"])
+                .fixed("tpdu", 4, ASCII)
+                .fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.fixed(4, "amount", 12, ASCII)))
+            .message("Response", |m| m.selector(&["xxxx0210"])
+                .fixed("tpdu", 4, ASCII)
+                .fixed("message_type", 4, ASCII)
+                .bitmap("bitmap", BINARY, |b| b.fixed(39, "action_code", 3, ASCII)))
+            .build().unwrap();
+
+        let mut iso_msg = new_msg(&spec, spec.get_message("Request").unwrap());
+        iso_msg.set("tpdu", "6000").unwrap();
+        iso_msg.set("message_type", "0200").unwrap();
+        iso_msg.set_on(4, "000000000029").unwrap();
+
+        let resp = iso_msg.response().unwrap();
+        assert_eq!(resp.msg.name(), "Response");
+        assert_eq!(resp.get("tpdu").unwrap(), "6000");
+        assert_eq!(resp.get("message_type").unwrap(), "0210");
+    }
+}
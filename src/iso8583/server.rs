@@ -1,4 +1,5 @@
 //! This module contains the implementation of a ISO server (TCP)
+use std::fmt;
 use std::io::{BufReader, Read, Write};
 use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::sync::Arc;
@@ -10,9 +11,28 @@ use crate::iso8583::IsoError;
 use crate::iso8583::iso_spec::{IsoMsg, Spec};
 use crate::iso8583::mli::{MLI, MLI2E, MLI2I, MLI4E, MLI4I, MLIType};
 
-/// This struct represents an error associated with server errors
-pub struct IsoServerError {
-    pub msg: String
+/// This enum represents an error associated with server errors
+#[derive(Debug)]
+pub enum IsoServerError {
+    /// The host_port could not be resolved (to a IPv4 address)
+    InvalidAddress { host_port: String, source: Option<std::io::Error> },
+}
+
+impl fmt::Display for IsoServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsoServerError::InvalidAddress { host_port, source: Some(e) } => write!(f, "invalid host_port: {}: cause: {}", host_port, e),
+            IsoServerError::InvalidAddress { host_port, source: None } => write!(f, "invalid host_port: {} : unresolvable?", host_port),
+        }
+    }
+}
+
+impl std::error::Error for IsoServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IsoServerError::InvalidAddress { source, .. } => source.as_ref().map(|e| e as &(dyn std::error::Error + 'static)),
+        }
+    }
 }
 
 /// This struct represents a IsoServer
@@ -21,19 +41,21 @@ pub struct ISOServer {
     sock_addr: Vec<SocketAddr>,
     pub(crate) mli: Arc<Box<dyn MLI>>,
     /// The specification associated with the server
-    pub spec: &'static crate::iso8583::iso_spec::Spec,
+    pub spec: Arc<Spec>,
     /// The message processor to be used to handle incoming requests
     pub(crate) msg_processor: Arc<Box<dyn MsgProcessor>>,
 }
 
 /// This trait whose implementation is used by the IsoServer to handle incoming requests
 pub trait MsgProcessor: Send + Sync {
-    fn process(&self, iso_server: &ISOServer, msg: &mut Vec<u8>) -> Result<(Vec<u8>, IsoMsg), IsoError>;
+    /// Handles the raw request msg and returns the assembled response along with the response message.
+    /// The response message may borrow from the spec of iso_server
+    fn process<'a>(&self, iso_server: &'a ISOServer, msg: &mut Vec<u8>) -> Result<(Vec<u8>, IsoMsg<'a>), IsoError>;
 }
 
 impl ISOServer {
     /// Returns a new ISO server on success or a IsoServer if the provided addr is incorrect
-    pub fn new<'a>(host_port: String, spec: &'static Spec, mli_type: MLIType, msg_processor: Box<dyn MsgProcessor>) -> Result<ISOServer, IsoServerError> {
+    pub fn new(host_port: String, spec: Arc<Spec>, mli_type: MLIType, msg_processor: Box<dyn MsgProcessor>) -> Result<ISOServer, IsoServerError> {
         let mli: Arc<Box<dyn MLI>>;
 
         match mli_type {
@@ -60,10 +82,10 @@ impl ISOServer {
                 if addrs.len() > 0 {
                     Ok(ISOServer { sock_addr: addrs, spec, mli, msg_processor: Arc::new(msg_processor) })
                 } else {
-                    Err(IsoServerError { msg: format!("invalid host_port: {} : unresolvable?", &host_port) })
+                    Err(IsoServerError::InvalidAddress { host_port, source: None })
                 }
             }
-            Err(e) => Err(IsoServerError { msg: format!("invalid host_port: {}: cause: {}", &host_port, e.to_string()) })
+            Err(e) => Err(IsoServerError::InvalidAddress { host_port, source: Some(e) })
         }
     }
 
@@ -71,7 +93,7 @@ impl ISOServer {
     pub fn start(&self) -> JoinHandle<()> {
         let server = ISOServer {
             sock_addr: self.sock_addr.clone(),
-            spec: self.spec,
+            spec: self.spec.clone(),
             mli: self.mli.clone(),
             msg_processor: self.msg_processor.clone(),
         };
@@ -92,7 +114,7 @@ impl ISOServer {
 fn new_client(iso_server: &ISOServer, stream_: TcpStream) {
     let server = ISOServer {
         sock_addr: iso_server.sock_addr.clone(),
-        spec: iso_server.spec,
+        spec: iso_server.spec.clone(),
         mli: iso_server.mli.clone(),
         msg_processor: iso_server.msg_processor.clone(),
     };
@@ -114,7 +136,7 @@ fn new_client(iso_server: &ISOServer, stream_: TcpStream) {
                         reading_mli = false;
                     }
                     Err(e) => {
-                        error!("client socket_err: {} {}", &stream.peer_addr().unwrap().to_string(), e.msg);
+                        error!("client socket_err: {:?} {}", &stream.peer_addr(), e);
                         break 'done;
                     }
                 };
@@ -143,12 +165,12 @@ fn new_client(iso_server: &ISOServer, stream_: TcpStream) {
                                     writer.flush().unwrap();
                                 }
                                 Err(e) => {
-                                    error!("failed to construct mli {}", e.msg)
+                                    error!("failed to construct mli {}", e)
                                 }
                             }
                         }
                         Err(e) => {
-                            error!("failed to handle incoming req - {}", e.msg)
+                            error!("failed to handle incoming req - {}", e)
                         }
                     }
                     mli = 0;
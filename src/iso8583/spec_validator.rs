@@ -0,0 +1,418 @@
+//! This module contains the validation of a spec definition (as read from a YAML file or built with a SpecBuilder)
+//! that is run before the definition is turned into a Spec
+use std::collections::HashSet;
+
+use crate::iso8583::field::{check_hex, Encoding};
+use crate::iso8583::iso_spec::SpecDiagnostic;
+use crate::iso8583::rules::Presence;
+use crate::iso8583::selector::Selector;
+use crate::iso8583::yaml_de::{rules_of, YField, YSpec};
+
+/// The highest position that can be defined on a bitmapped field
+const MAX_POSITION: u32 = 192;
+
+/// The kind of field that contains the field being validated
+#[derive(Clone, Copy, PartialEq)]
+enum Container {
+    /// The header or a message segment
+    Segment,
+    /// A bitmapped field
+    Bitmap,
+    /// A fixed or variable field with subfields
+    Composite,
+}
+
+struct Validator<'a> {
+    // lines of the YAML source (if any) used to report the line of a problem
+    lines: Vec<&'a str>,
+    // index of the line of the element being validated, elements are visited in the order
+    // they appear in the source
+    cursor: usize,
+    // index of the line after the element whose children are being validated, elements are only looked up
+    // before it (so that a element that is not in the source, like one of a base spec, is not given the line
+    // of a later element with the same name)
+    end: usize,
+    diagnostics: Vec<SpecDiagnostic>,
+    // line, path and condition of the conditional fields of the segment being validated, these are checked
+    // once all fields of the segment are known
+    conditions: Vec<(Option<usize>, String, String)>,
+}
+
+/// Validates the spec definition and returns all problems found (an empty Vec if there are none)
+pub(in crate::iso8583) fn validate_spec(y_spec: &YSpec, src: Option<&str>) -> Vec<SpecDiagnostic> {
+    let lines: Vec<&str> = src.map(|s| s.lines().collect()).unwrap_or_default();
+    let mut validator = Validator {
+        end: lines.len(),
+        lines,
+        cursor: 0,
+        diagnostics: vec![],
+        conditions: vec![],
+    };
+
+    let line = validator.enter_section("header_fields");
+    if y_spec.header_fields.is_empty() {
+        validator.report(line, "header_fields", "spec must define at least one header field");
+    }
+    let end = validator.within(line);
+    let mut names = HashSet::new();
+    for f in &y_spec.header_fields {
+        validator.check_field(f, "header_fields", Container::Segment, &mut names, &mut HashSet::new());
+    }
+    validator.end = end;
+
+    let line = validator.enter_section("messages");
+    if y_spec.messages.is_empty() {
+        validator.report(line, "messages", "spec must define at least one message");
+    }
+    validator.within(line);
+
+    let mut seg_names = HashSet::new();
+    let mut selectors = HashSet::new();
+    for seg in &y_spec.messages {
+        let seg_path = format!("messages[{}]", seg.name);
+        let line = validator.enter(&seg.name);
+
+        if !seg_names.insert(seg.name.as_str()) {
+            validator.report(line, &seg_path, "duplicate message name");
+        }
+        if seg.selector.is_empty() {
+            validator.report(line, &seg_path, "message must define at least one selector");
+        }
+        for s in &seg.selector {
+            if !selectors.insert(s.as_str()) {
+                validator.report(line, &seg_path, format!("selector {} is used by more than one message", s).as_str());
+            }
+            match Selector::parse(s) {
+                // the fields a selector refers to are checked along with the conditions of the fields
+                Ok(selector) => selector.conditions().into_iter()
+                    .for_each(|when| validator.conditions.push((line, seg_path.clone(), when))),
+                Err(e) => validator.report(line, &seg_path, format!("selector {} is not valid - {}", s, e).as_str())
+            }
+        }
+
+        let end = validator.within(line);
+        let mut names = HashSet::new();
+        for f in &seg.fields {
+            validator.check_field(f, seg_path.as_str(), Container::Segment, &mut names, &mut HashSet::new());
+        }
+        validator.end = end;
+
+        for (line, path, when) in std::mem::take(&mut validator.conditions) {
+            let field = when.split(['=', '.']).next().unwrap_or_default();
+            if field.parse::<u32>().is_err() && !names.contains(field) {
+                validator.report(line, &path, format!("condition {} refers to a undefined field", when).as_str());
+            }
+        }
+    }
+
+    validator.diagnostics
+}
+
+impl<'a> Validator<'a> {
+    fn check_field<'b>(&mut self, f: &'b YField, parent_path: &str, container: Container, names: &mut HashSet<&'b str>, positions: &mut HashSet<u32>) {
+        let path = format!("{}.{}", parent_path, f.name);
+        let line = self.enter(&f.name);
+
+        if f.name.is_empty() {
+            self.report(line, &path, "field name cannot be empty");
+        } else if !names.insert(f.name.as_str()) {
+            self.report(line, &path, "duplicate field name");
+        }
+
+        match (container, f.position) {
+            (Container::Segment, _) => {}
+            (Container::Bitmap, None) => self.report(line, &path, "field within a bitmap requires a position"),
+            (Container::Bitmap, Some(1)) | (Container::Bitmap, Some(65)) => {
+                self.report(line, &path, "positions 1 and 65 are reserved for the secondary and tertiary bitmaps")
+            }
+            (Container::Bitmap, Some(pos)) if pos == 0 || pos > MAX_POSITION => {
+                self.report(line, &path, format!("position {} is outside of 2..{}", pos, MAX_POSITION).as_str())
+            }
+            (Container::Composite, None) => {
+                if f.field_type != "Bitmapped" {
+                    self.report(line, &path, "field within a fixed or variable field requires a position")
+                }
+            }
+            (Container::Composite, Some(0)) => self.report(line, &path, "position of a subfield should be greater than 0"),
+            (_, Some(pos)) => {
+                if !positions.insert(pos) {
+                    self.report(line, &path, format!("duplicate position {}", pos).as_str());
+                }
+            }
+        }
+
+        if (f.pad_char.is_some() || f.justify.is_some() || f.overflow.is_some()) && f.field_type != "Fixed" {
+            self.report(line, &path, "pad_char, justify and overflow are only supported on fixed fields");
+        }
+        if f.pad_char.map(|c| !c.is_ascii()).unwrap_or(false) {
+            self.report(line, &path, "pad_char should be a ASCII character");
+        }
+
+        if (f.content.is_some() || f.min_len.is_some() || f.max_len.is_some()) && f.field_type != "Fixed" && f.field_type != "Variable" {
+            self.report(line, &path, "content, min_len and max_len are only supported on fixed and variable fields");
+        }
+        if let (Some(min_len), Some(max_len)) = (f.min_len, f.max_len) {
+            if min_len > max_len {
+                self.report(line, &path, "min_len cannot be greater than max_len");
+            }
+        }
+
+        match (f.presence, &f.when) {
+            (Some(_), _) if container != Container::Bitmap => self.report(line, &path, "presence is only supported on fields within a bitmap"),
+            (Some(Presence::Conditional), None) => self.report(line, &path, "a conditional field requires a condition (when)"),
+            (Some(Presence::Conditional), Some(when)) => self.conditions.push((line, path.clone(), when.clone())),
+            (_, Some(_)) => self.report(line, &path, "when is only supported on conditional fields"),
+            _ => {}
+        }
+
+        if (f.default.is_some() || f.generator.is_some()) && f.field_type != "Fixed" && f.field_type != "Variable" {
+            self.report(line, &path, "default and generator are only supported on fixed and variable fields");
+        } else if f.default.is_some() && f.generator.is_some() {
+            self.report(line, &path, "a field cannot have both a default and a generator");
+        }
+        if let Some(Err(e)) = f.default.as_ref().map(|d| check_hex(&f.data_encoding, &f.bcd_padding, d).and_then(|_| rules_of(f).verify(d))) {
+            self.report(line, &path, format!("default is not valid - {}", e).as_str());
+        }
+
+        if f.len_unit.is_some() && f.field_type != "Variable" {
+            self.report(line, &path, "len_unit is only supported on variable fields");
+        }
+
+        if f.bcd_padding.is_some() {
+            if f.field_type != "Fixed" && f.field_type != "Variable" {
+                self.report(line, &path, "bcd_padding is only supported on fixed and variable fields");
+            } else if !matches!(f.data_encoding, Encoding::BCD) {
+                self.report(line, &path, "bcd_padding requires a BCD data_encoding");
+            }
+        }
+
+        let children = f.children.as_deref().unwrap_or_default();
+        match f.field_type.as_str() {
+            "Fixed" => {
+                if f.len == 0 {
+                    self.report(line, &path, "fixed field requires a non-zero len");
+                }
+                let children_len: u32 = children.iter().map(|c| if c.field_type == "Fixed" { c.len } else { 0 }).sum();
+                if children_len > f.len {
+                    self.report(line, &path, format!("subfields require {} bytes but len is {}", children_len, f.len).as_str());
+                }
+                if children.iter().any(|c| c.field_type == "Bitmapped") {
+                    self.report(line, &path, "bitmapped fields can only be nested within a variable field");
+                }
+            }
+            "Variable" | "Tlv" | "AsciiTlv" => {
+                if f.field_type != "Variable" && !children.is_empty() {
+                    self.report(line, &path, "tlv fields cannot have children");
+                }
+                if f.field_type == "AsciiTlv" {
+                    match &f.tlv_format {
+                        None => self.report(line, &path, "AsciiTlv field requires a tlv_format"),
+                        Some(fmt) => {
+                            if fmt.tag_len == 0 {
+                                self.report(line, &path, "tag_len of a tlv_format should be greater than 0");
+                            }
+                            if let Some(msg) = check_len_ind(&fmt.len_encoding, fmt.len_len) {
+                                self.report(line, &path, format!("len_len of a tlv_format - {}", msg).as_str());
+                            }
+                        }
+                    }
+                }
+                if children.len() > 1 && children.iter().any(|c| c.field_type == "Bitmapped") {
+                    self.report(line, &path, "a bitmapped subfield should be the only subfield of a variable field");
+                }
+                match &f.len_encoding {
+                    None => self.report(line, &path, "variable field requires a len_encoding"),
+                    Some(len_encoding) => {
+                        if let Some(msg) = check_len_ind(len_encoding, f.len) {
+                            self.report(line, &path, format!("len of a {}", msg).as_str());
+                        }
+                    }
+                }
+            }
+            "Bitmapped" => {
+                match container {
+                    Container::Bitmap => self.report(line, &path, "bitmapped fields cannot be nested within a bitmap"),
+                    Container::Composite | Container::Segment => {}
+                }
+
+                self.check_children(line, children, path.as_str(), Container::Bitmap, names);
+                return;
+            }
+            _ => {
+                self.report(line, &path, format!("unsupported field type - {}", f.field_type).as_str());
+                return;
+            }
+        }
+
+        self.check_children(line, children, path.as_str(), Container::Composite, names);
+    }
+
+    /// Checks the children of the field at line, they are looked up within the lines of the field
+    fn check_children<'b>(&mut self, line: Option<usize>, children: &'b [YField], path: &str, container: Container, names: &mut HashSet<&'b str>) {
+        let end = self.within(line);
+        let mut positions = HashSet::new();
+        for c in children {
+            self.check_field(c, path, container, names, &mut positions);
+        }
+        self.end = end;
+    }
+
+    fn report(&mut self, line: Option<usize>, path: &str, msg: &str) {
+        self.diagnostics.push(SpecDiagnostic { message: msg.to_string(), path: path.to_string(), line });
+    }
+
+    /// Moves to the top level key section and returns its (1 based) line
+    fn enter_section(&mut self, section: &str) -> Option<usize> {
+        let section_key = format!("{}:", section);
+        let idx = self.lines.iter().position(|l| l.trim_end() == section_key)?;
+        self.cursor = idx;
+        Some(idx + 1)
+    }
+
+    /// Moves to the next element with the given name and returns its (1 based) line
+    fn enter(&mut self, name: &str) -> Option<usize> {
+        let idx = self.cursor + self.lines.get(self.cursor..self.end)?.iter().position(|l| name_of(l) == Some(name))?;
+        self.cursor = idx;
+        Some(idx + 1)
+    }
+
+    /// Limits the lookup of elements to the lines of the element at line (to none if it is not in the source)
+    /// and returns the previous limit
+    fn within(&mut self, line: Option<usize>) -> usize {
+        let end = match line {
+            Some(line) => self.end_of(line - 1),
+            None => self.cursor
+        };
+        std::mem::replace(&mut self.end, end)
+    }
+
+    /// Returns the index of the line after the element that starts at idx - the next line that is not
+    /// indented more (a list item also ends at the next item at its indentation)
+    fn end_of(&self, idx: usize) -> usize {
+        let (indent, item) = layout_of(self.lines[idx]);
+        self.lines.get(idx + 1..self.end).unwrap_or_default().iter()
+            .position(|l| {
+                let trimmed = l.trim();
+                let (i, next_item) = layout_of(l);
+                !trimmed.is_empty() && !trimmed.starts_with('#') && (i < indent || (i == indent && (item || !next_item)))
+            })
+            .map_or(self.end, |i| idx + 1 + i)
+    }
+}
+
+/// Returns a problem with the length of a length indicator (if any)
+fn check_len_ind(encoding: &Encoding, len: u32) -> Option<&'static str> {
+    match encoding {
+        Encoding::ASCII | Encoding::EBCDIC if len == 0 || len > 6 => Some("ASCII/EBCDIC length indicator should be between 1 and 6"),
+        Encoding::BINARY if len == 0 || len > 4 => Some("BINARY length indicator should be between 1 and 4"),
+        Encoding::BCD if len == 0 || len > 3 => Some("BCD length indicator should be between 1 and 3"),
+        _ => None
+    }
+}
+
+/// Returns the value of the name attribute (or of the ref to the field dictionary) if the line defines one
+fn name_of(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let line = line.strip_prefix('-').unwrap_or(line).trim_start();
+    let name = line.strip_prefix("name:").or_else(|| line.strip_prefix("ref:"))?.trim();
+    Some(name.trim_matches(|c| c == '"' || c == '\''))
+}
+
+/// Returns the indentation of line and whether it starts a list item
+fn layout_of(line: &str) -> (usize, bool) {
+    let trimmed = line.trim_start();
+    (line.len() - trimmed.len(), trimmed.starts_with('-'))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::iso_spec::Spec;
+
+    const BAD_SPEC: &str = r#"name: BadSpec
+id: 1
+header_fields:
+  - name: "message_type"
+    id: 1
+    type: Fixed
+    len: 4
+    data_encoding: ASCII
+messages:
+  - name: "Auth"
+    id: 1
+    selector: ["1100"]
+    fields:
+      - name: "message_type"
+        id: 1
+        type: Fixd
+        len: 4
+        data_encoding: ASCII
+      - name: "bitmap"
+        id: 2
+        type: Bitmapped
+        len: 0
+        data_encoding: BINARY
+        children:
+          - name: "pan"
+            id: 3
+            type: Variable
+            len: 2
+            data_encoding: ASCII
+            position: 2
+          - name: "amount"
+            id: 4
+            type: Fixed
+            len: 12
+            data_encoding: ASCII
+            position: 2
+          - name: "pan"
+            id: 5
+            type: Fixed
+            len: 4
+            data_encoding: ASCII
+            position: 200
+            children:
+              - name: "sub"
+                id: 1
+                type: Fixed
+                len: 6
+                data_encoding: ASCII
+  - name: "Reversal"
+    id: 2
+    selector: ["1100"]
+    fields: []
+"#;
+
+    #[test]
+    fn test_validation_diagnostics() {
+        let err = match Spec::from_yaml_str(BAD_SPEC) {
+            Ok(_) => panic!("spec should have failed validation"),
+            Err(e) => e
+        };
+
+        let found: Vec<(&str, &str, Option<usize>)> = err.diagnostics.iter()
+            .map(|d| (d.path.as_str(), d.message.as_str(), d.line)).collect();
+
+        assert_eq!(found, vec![
+            ("messages[Auth].message_type", "unsupported field type - Fixd", Some(14)),
+            ("messages[Auth].bitmap.pan", "variable field requires a len_encoding", Some(25)),
+            ("messages[Auth].bitmap.amount", "duplicate position 2", Some(31)),
+            ("messages[Auth].bitmap.pan", "duplicate field name", Some(37)),
+            ("messages[Auth].bitmap.pan", "position 200 is outside of 2..192", Some(37)),
+            ("messages[Auth].bitmap.pan", "subfields require 6 bytes but len is 4", Some(37)),
+            ("messages[Auth].bitmap.pan.sub", "field within a fixed or variable field requires a position", Some(44)),
+            ("messages[Reversal]", "selector 1100 is used by more than one message", Some(49)),
+        ]);
+    }
+
+    #[test]
+    fn test_yaml_syntax_error() {
+        match Spec::from_yaml_str("name: [") {
+            Ok(_) => panic!("spec should have failed to load"),
+            Err(e) => {
+                assert_eq!(e.diagnostics.len(), 1);
+                assert!(e.diagnostics[0].line.is_some());
+            }
+        }
+    }
+}
@@ -19,11 +19,11 @@ mod tests {
         let path = Path::new(".").join("sample_spec").join("sample_spec.yaml");
         std::env::set_var("SPEC_FILE", path.to_str().unwrap());
 
-        let spec = crate::iso8583::iso_spec::spec("");
+        let spec = crate::iso8583::iso_spec::spec("SampleSpec")?;
         let msg_seg = spec.get_message_from_header("1100").unwrap();
 
 
-        let mut iso_msg = iso_spec::new_msg(spec, msg_seg);
+        let mut iso_msg = iso_spec::new_msg(&spec, msg_seg);
 
         iso_msg.set("message_type", "1100").unwrap();
         iso_msg.set_on(2, "4567909845671235").unwrap();
@@ -86,14 +86,14 @@ mod tests {
         let path = Path::new(".").join("sample_spec").join("sample_spec.yaml");
         std::env::set_var("SPEC_FILE", path.to_str().unwrap());
 
-        let spec = crate::iso8583::iso_spec::spec("");
+        let spec = crate::iso8583::iso_spec::spec("SampleSpec")?;
         let msg_seg = spec.get_message_from_header("1420").unwrap();
 
         let mut client = ISOTcpClient::new("localhost:6666", &spec, MLI2E);
 
         //send 10 messages
         for _ in 1..10 {
-            let mut iso_msg = iso_spec::new_msg(spec, msg_seg);
+            let mut iso_msg = iso_spec::new_msg(&spec, msg_seg);
 
             iso_msg.set("message_type", "1420").unwrap();
             iso_msg.set_on(2, "123456789101").unwrap();
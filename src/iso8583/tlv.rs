@@ -0,0 +1,452 @@
+//! This module contains implementation of TLV data elements and the Tlv fields - BER-TLV (for example - EMV ICC data
+//! in DE55) and TLV with fixed width ASCII tags and lengths (for example - Mastercard PDS in DE48)
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use crate::iso8583::field::{check_hex, ContentError, decode_len, encode_len, Encoding, Field, ParseError, string_to_vec, VarField, vec_to_string};
+use crate::iso8583::iso_spec::IsoMsg;
+use crate::iso8583::trace::ParseTrace;
+
+/// The deepest nesting of constructed elements that is parsed, deeper elements fail to parse (so that
+/// hostile data cannot exhaust the stack)
+const MAX_DEPTH: usize = 16;
+
+/// This struct represents a BER-TLV data element. The children of a constructed element
+/// (like a template - 70 or 77) are the elements within its value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tlv {
+    /// The tag (including subsequent tag bytes, if any)
+    pub tag: Vec<u8>,
+    /// The value of the element, for a constructed element this is the encoding of its children
+    pub value: Vec<u8>,
+    /// The elements within a constructed element (empty for a primitive element)
+    pub children: Vec<Tlv>,
+}
+
+impl Tlv {
+    /// Creates a new data element, the children of a constructed element are parsed from value
+    pub fn new(tag: &[u8], value: &[u8]) -> Result<Tlv, usize> {
+        Tlv::new_at(tag, value, 0)
+    }
+
+    /// Creates a new data element nested depth levels deep within constructed elements
+    fn new_at(tag: &[u8], value: &[u8], depth: usize) -> Result<Tlv, usize> {
+        let mut tlv = Tlv { tag: tag.to_vec(), value: vec![], children: vec![] };
+        tlv.set_value_at(value, depth)?;
+        Ok(tlv)
+    }
+
+    /// Returns the tag as a (upper case) hex string like 9F26
+    pub fn tag_hex(&self) -> String {
+        hex::encode_upper(&self.tag)
+    }
+
+    /// Returns true if this is a constructed element i.e. its value is made of data elements
+    pub fn is_constructed(&self) -> bool {
+        !self.tag.is_empty() && self.tag[0] & 0x20 == 0x20
+    }
+
+    /// Returns the element (tag, length and value) in wire format
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = self.tag.clone();
+        out.extend(encode_ber_len(self.value.len()));
+        out.extend(&self.value);
+        out
+    }
+
+    /// Sets the value of the element, for a constructed element the children are parsed from value
+    /// (Returns the offset within value of the element that could not be parsed on failure)
+    fn set_value(&mut self, value: &[u8]) -> Result<(), usize> {
+        self.set_value_at(value, 0)
+    }
+
+    fn set_value_at(&mut self, value: &[u8], depth: usize) -> Result<(), usize> {
+        if self.is_constructed() {
+            self.children = parse_tlv_at(value, depth + 1)?;
+        }
+        self.value = value.to_vec();
+        Ok(())
+    }
+}
+
+/// Parses BER-TLV data elements from data, returns the offset of the element that could not be parsed on failure.
+/// Padding (0x00 and 0xFF) between elements is skipped. Constructed elements nested more than MAX_DEPTH levels
+/// deep fail to parse
+pub fn parse_tlv(data: &[u8]) -> Result<Vec<Tlv>, usize> {
+    parse_tlv_at(data, 0)
+}
+
+/// Parses the data elements in data, which is nested depth levels deep within constructed elements
+fn parse_tlv_at(data: &[u8], depth: usize) -> Result<Vec<Tlv>, usize> {
+    if depth > MAX_DEPTH {
+        return Err(0);
+    }
+    let mut elements = vec![];
+    let mut i = 0;
+
+    while i < data.len() {
+        if data[i] == 0x00 || data[i] == 0xFF {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        // tag - if the low 5 bits of the first byte are set, the tag continues until a byte with the high bit clear
+        i += 1;
+        if data[start] & 0x1F == 0x1F {
+            loop {
+                match data.get(i) {
+                    Some(b) => {
+                        i += 1;
+                        if b & 0x80 == 0 {
+                            break;
+                        }
+                    }
+                    None => return Err(start)
+                }
+            }
+        }
+        let tag = &data[start..i];
+
+        // length - short form (< 0x80) or long form where the low 7 bits are the number of subsequent length bytes
+        let len = match data.get(i) {
+            Some(b) if *b < 0x80 => {
+                i += 1;
+                *b as usize
+            }
+            Some(b) if *b > 0x80 && *b <= 0x84 => {
+                let n = (*b & 0x7F) as usize;
+                if i + 1 + n > data.len() {
+                    return Err(start);
+                }
+                let len = data[i + 1..i + 1 + n].iter().fold(0usize, |l, b| l << 8 | *b as usize);
+                i += 1 + n;
+                len
+            }
+            // indefinite (0x80) and lengths over 4 bytes are not supported
+            _ => return Err(start)
+        };
+
+        if i + len > data.len() {
+            return Err(start);
+        }
+
+        let tlv = Tlv::new_at(tag, &data[i..i + len], depth).map_err(|offset| i + offset)?;
+        elements.push(tlv);
+        i += len;
+    }
+
+    Ok(elements)
+}
+
+/// Returns the data elements in wire format (in the order they appear in elements)
+pub fn assemble_tlv(elements: &[Tlv]) -> Vec<u8> {
+    elements.iter().flat_map(|e| e.to_vec()).collect()
+}
+
+/// Returns the first element (searching depth first, within constructed elements as well) with the given tag
+pub fn find_tag<'a>(elements: &'a [Tlv], tag: &[u8]) -> Option<&'a Tlv> {
+    for e in elements {
+        if e.tag == tag {
+            return Some(e);
+        }
+        if let Some(c) = find_tag(&e.children, tag) {
+            return Some(c);
+        }
+    }
+    None
+}
+
+/// Sets the value of the element with the given tag (wherever it is in the tree of elements), a new element is
+/// appended to the top level elements if there is none. The values of the enclosing constructed elements are updated
+pub fn set_tag(elements: &mut Vec<Tlv>, tag: &[u8], value: &[u8]) -> Result<(), usize> {
+    if !replace_tag(elements, tag, value)? {
+        elements.push(Tlv::new(tag, value)?);
+    }
+    Ok(())
+}
+
+fn replace_tag(elements: &mut [Tlv], tag: &[u8], value: &[u8]) -> Result<bool, usize> {
+    for e in elements.iter_mut() {
+        if e.tag == tag {
+            e.set_value(value)?;
+            return Ok(true);
+        }
+        if replace_tag(&mut e.children, tag, value)? {
+            e.value = assemble_tlv(&e.children);
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the BER encoding of a length
+fn encode_ber_len(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        return vec![len as u8];
+    }
+    let bytes: Vec<u8> = (len as u32).to_be_bytes().iter().skip_while(|b| **b == 0).cloned().collect();
+    let mut out = vec![0x80 | bytes.len() as u8];
+    out.extend(bytes);
+    out
+}
+
+
+/// This enum represents the format of the data elements of a TlvField
+#[derive(Debug, Clone, Copy)]
+pub enum TlvFormat {
+    /// BER-TLV (as used in EMV ICC data)
+    Ber,
+    /// Tags and lengths of a fixed width (like Mastercard PDS - a 2 digit tag and a 2 digit length), the
+    /// length is the number of bytes of the value
+    Ascii { tag_len: u32, tag_encoding: Encoding, len_len: u32, len_encoding: Encoding },
+}
+
+/// This struct represents a field whose data is a list of TLV data elements. The data is preceded
+/// by a length indicator just like a Variable field
+pub struct TlvField {
+    /// The variable field that carries the data elements
+    pub var: VarField,
+    /// Format of the data elements
+    pub format: TlvFormat,
+}
+
+impl TlvField {
+    /// Parses the data elements of the field from data, returns the offset of the element that could not be parsed on failure
+    pub fn parse_elements(&self, data: &[u8]) -> Result<Vec<Tlv>, usize> {
+        match self.format {
+            TlvFormat::Ber => parse_tlv(data),
+            TlvFormat::Ascii { tag_len, len_len, len_encoding, .. } => {
+                let (tag_len, len_len) = (tag_len as usize, len_len as usize);
+                let mut elements = vec![];
+                let mut i = 0;
+
+                while i < data.len() {
+                    let start = i;
+                    if i + tag_len + len_len > data.len() {
+                        return Err(start);
+                    }
+                    let tag = &data[i..i + tag_len];
+                    i += tag_len;
+
+                    let len = decode_len(&len_encoding, &data[i..i + len_len]).ok_or(start)?;
+                    i += len_len;
+                    if i + len > data.len() {
+                        return Err(start);
+                    }
+
+                    elements.push(Tlv { tag: tag.to_vec(), value: data[i..i + len].to_vec(), children: vec![] });
+                    i += len;
+                }
+                Ok(elements)
+            }
+        }
+    }
+
+    /// Returns the data elements in wire format (in the order they appear in elements), or the index
+    /// of the element whose value is too long for the format
+    pub fn assemble_elements(&self, elements: &[Tlv]) -> Result<Vec<u8>, usize> {
+        match self.format {
+            TlvFormat::Ber => Ok(assemble_tlv(elements)),
+            TlvFormat::Ascii { len_len, len_encoding, .. } => {
+                let mut out = vec![];
+                for (i, e) in elements.iter().enumerate() {
+                    out.extend(&e.tag);
+                    out.extend(encode_len(&len_encoding, len_len, e.value.len()).ok_or(i)?);
+                    out.extend(&e.value);
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    /// Sets the value of the element with the given tag or appends a new element if there is none. For BER-TLV,
+    /// elements within constructed elements are updated as well (see set_tag)
+    pub fn set_element(&self, elements: &mut Vec<Tlv>, tag: &[u8], value: &[u8]) -> Result<(), usize> {
+        match self.format {
+            TlvFormat::Ber => set_tag(elements, tag, value),
+            TlvFormat::Ascii { .. } => {
+                match elements.iter_mut().find(|e| e.tag == tag) {
+                    Some(e) => e.value = value.to_vec(),
+                    None => elements.push(Tlv { tag: tag.to_vec(), value: value.to_vec(), children: vec![] })
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the tag in wire format given its string form (hex for BER-TLV and binary tags, like 9F26) or
+    /// None if the tag is not valid for the format
+    pub fn tag_to_raw(&self, tag: &str) -> Option<Vec<u8>> {
+        match self.format {
+            TlvFormat::Ber => hex::decode(tag).ok().filter(|t| !t.is_empty()),
+            TlvFormat::Ascii { tag_len, tag_encoding, .. } => {
+                to_raw_checked(&tag_encoding, tag).filter(|t| t.len() == tag_len as usize)
+            }
+        }
+    }
+
+    /// Returns a string that represents the tag (hex for BER-TLV)
+    pub fn tag_to_string(&self, tag: &[u8]) -> String {
+        match self.format {
+            TlvFormat::Ber => hex::encode_upper(tag),
+            TlvFormat::Ascii { tag_encoding, .. } => vec_to_string(&tag_encoding, &tag.to_vec())
+        }
+    }
+
+    /// Returns a string that represents the value of an element (hex for BER-TLV)
+    pub fn value_to_string(&self, value: &[u8]) -> String {
+        match self.format {
+            TlvFormat::Ber => hex::encode_upper(value),
+            TlvFormat::Ascii { .. } => vec_to_string(&self.var.encoding, &value.to_vec())
+        }
+    }
+
+    /// Returns the value of an element in wire format or None if val is not valid for the format
+    pub fn value_to_raw(&self, val: &str) -> Option<Vec<u8>> {
+        match self.format {
+            TlvFormat::Ber => hex::decode(val).ok(),
+            TlvFormat::Ascii { .. } => to_raw_checked(&self.var.encoding, val)
+        }
+    }
+}
+
+/// Returns val in wire format as per the encoding, or None if it is not valid hex for BINARY/BCD encoding
+fn to_raw_checked(encoding: &Encoding, val: &str) -> Option<Vec<u8>> {
+    match encoding {
+        Encoding::BINARY | Encoding::BCD => hex::decode(val).ok(),
+        _ => Some(string_to_vec(encoding, val))
+    }
+}
+
+impl Field for TlvField {
+    fn name(&self) -> &String {
+        &self.var.name
+    }
+
+    fn parse(&self, in_buf: &mut Cursor<&[u8]>, f2d_map: &mut HashMap<String, Vec<u8>>, trace: &mut ParseTrace) -> Result<(), ParseError> {
+        let offset = in_buf.position() as usize;
+        self.var.parse(in_buf, f2d_map, trace)?;
+
+        // the data is verified to be valid as per the format
+        let data_offset = offset + self.var.len as usize;
+        self.parse_elements(&f2d_map[&self.var.name]).map(|_| ())
+            .map_err(|e| ParseError::InvalidEncoding { field: self.var.name.clone(), offset: data_offset + e })
+    }
+
+    fn assemble(&self, out_buf: &mut Vec<u8>, iso_msg: &IsoMsg) -> Result<u32, ParseError> {
+        self.var.assemble(out_buf, iso_msg)
+    }
+
+    fn position(&self) -> u32 {
+        self.var.position
+    }
+
+    fn children(&self) -> Vec<&dyn Field> {
+        vec![]
+    }
+
+    fn child_by_pos(&self, _pos: u32) -> Option<&dyn Field> {
+        None
+    }
+
+    fn child_by_name(&self, _name: &String) -> Option<&dyn Field> {
+        None
+    }
+
+    fn to_string(&self, data: &Vec<u8>) -> String {
+        self.var.to_string(data)
+    }
+
+    fn to_raw(&self, val: &str) -> Vec<u8> {
+        self.var.to_raw(val)
+    }
+
+    fn check(&self, val: &str) -> Result<(), ContentError> {
+        // only values that can be read back as data elements are accepted
+        check_hex(&self.var.encoding, &None, val)?;
+        self.parse_elements(&self.to_raw(val)).map(|_| ()).map_err(|offset| ContentError::InvalidTlv { offset })
+    }
+
+    fn as_tlv(&self) -> Option<&TlvField> {
+        Some(self)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::iso8583::field::Encoding::{BINARY, EBCDIC};
+    use crate::iso8583::field::{ContentRules, VarField};
+    use crate::iso8583::tlv::{assemble_tlv, find_tag, parse_tlv, set_tag, Tlv, TlvField, TlvFormat};
+
+    #[test]
+    fn test_parse_tlv() {
+        // 9F26 (multi-byte tag), 77 (constructed) containing 9F27 and 9F36 and 91 with a long form length (0x82)
+        let mut data = hex::decode("9F2608C2A2D6F5C1A0F19A77099F2701809F3602002191820102").unwrap();
+        data.extend(vec![0xAB; 0x102]);
+
+        let elements = parse_tlv(&data).unwrap();
+        assert_eq!(elements.len(), 3);
+        assert_eq!(elements[0].tag_hex(), "9F26");
+        assert_eq!(hex::encode_upper(&elements[0].value), "C2A2D6F5C1A0F19A");
+        assert!(elements[1].is_constructed());
+        assert_eq!(elements[1].children.iter().map(|e| e.tag_hex()).collect::<Vec<String>>(), vec!["9F27", "9F36"]);
+        assert_eq!(find_tag(&elements, &[0x9F, 0x36]).unwrap().value, vec![0x00, 0x21]);
+        assert_eq!(elements[2].value.len(), 0x102);
+        assert_eq!(assemble_tlv(&elements), data);
+    }
+
+    #[test]
+    fn test_parse_bad_tlv() {
+        // length runs past the data
+        assert_eq!(parse_tlv(&hex::decode("9F2608C2A2").unwrap()), Err(0));
+        // tag is incomplete
+        assert_eq!(parse_tlv(&hex::decode("950500000080009F").unwrap()), Err(7));
+        // bad element within a constructed element
+        assert_eq!(parse_tlv(&hex::decode("70049F2705FF").unwrap()), Err(2));
+        // indefinite length
+        assert_eq!(parse_tlv(&hex::decode("9F2680").unwrap()), Err(0));
+
+        // constructed elements nested too deep (the value of the innermost element is at offset 2 * 17)
+        let nested = |depth: usize| (0..depth).fold(vec![], |value, _| Tlv { tag: vec![0x70], value, children: vec![] }.to_vec());
+        assert!(parse_tlv(&nested(16)).is_ok());
+        assert_eq!(parse_tlv(&nested(17)), Err(34));
+        assert!(parse_tlv(&nested(3000)).is_err());
+    }
+
+    #[test]
+    fn test_set_tag() {
+        let mut elements = parse_tlv(&hex::decode("950500000080007703910100").unwrap()).unwrap();
+        set_tag(&mut elements, &[0x95], &[0x00, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        set_tag(&mut elements, &[0x91], &[0x01, 0x02]).unwrap();
+        set_tag(&mut elements, &[0x9F, 0x02], &[0x00, 0x00, 0x00, 0x00, 0x01, 0x00]).unwrap();
+        assert_eq!(hex::encode_upper(assemble_tlv(&elements)), "950500000000007704910201029F0206000000000100");
+
+        assert_eq!(Tlv::new(&[0x70], &[0x9F]), Err(0));
+    }
+
+    #[test]
+    fn test_fixed_width_elements() {
+        let tlv_field = TlvField {
+            var: VarField { name: "private".to_string(), id: 1, len: 1, len_encoding: BINARY, encoding: EBCDIC, position: 63, children: vec![], bcd_padding: None, len_unit: None, rules: ContentRules::default() },
+            format: TlvFormat::Ascii { tag_len: 3, tag_encoding: EBCDIC, len_len: 1, len_encoding: BINARY },
+        };
+
+        let mut elements = vec![];
+        tlv_field.set_element(&mut elements, &tlv_field.tag_to_raw("001").unwrap(), &tlv_field.value_to_raw("AB").unwrap()).unwrap();
+        tlv_field.set_element(&mut elements, &tlv_field.tag_to_raw("002").unwrap(), &[]).unwrap();
+        let data = tlv_field.assemble_elements(&elements).unwrap();
+        assert_eq!(hex::encode(&data), "f0f0f102c1c2f0f0f200");
+
+        let parsed = tlv_field.parse_elements(&data).unwrap();
+        assert_eq!(parsed, elements);
+        assert_eq!(tlv_field.tag_to_string(&parsed[0].tag), "001");
+        assert_eq!(tlv_field.value_to_string(&parsed[0].value), "AB");
+        assert!(tlv_field.tag_to_raw("01").is_none());
+        assert_eq!(tlv_field.parse_elements(&data[..4]), Err(0));
+
+        elements[0].value = vec![0; 256];
+        assert_eq!(tlv_field.assemble_elements(&elements), Err(0));
+    }
+}
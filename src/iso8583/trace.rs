@@ -0,0 +1,147 @@
+//! This module contains the types used to trace the parsing of a message (see Spec::parse_traced), which
+//! is useful to find out why (and where) a message does not match its spec
+use std::fmt::{Display, Formatter};
+
+use hexdump::sanitize_byte;
+
+use crate::iso8583::field::{Field, ParseError};
+use crate::iso8583::iso_spec::IsoMsg;
+
+/// This struct represents a field as it was read from the message
+#[derive(Debug)]
+pub struct FieldTrace {
+    /// Name of the field
+    pub name: String,
+    /// Position of the field in the bitmap (0 if not a bitmap child)
+    pub position: u32,
+    /// Offset of the field (including the length indicator) in the message
+    pub offset: usize,
+    /// Length indicator bytes (empty if the field has no length indicator)
+    pub len_ind: Vec<u8>,
+    /// Data bytes of the field
+    pub data: Vec<u8>,
+    /// Decoded value of the field
+    pub value: String,
+}
+
+/// This struct collects a FieldTrace for each field parsed, when enabled
+pub struct ParseTrace {
+    enabled: bool,
+    fields: Vec<FieldTrace>,
+}
+
+impl ParseTrace {
+    /// Creates a new ParseTrace, nothing is recorded unless enabled is true
+    pub fn new(enabled: bool) -> ParseTrace {
+        ParseTrace { enabled, fields: vec![] }
+    }
+
+    /// Records a field that was read at offset
+    pub fn record(&mut self, field: &dyn Field, offset: usize, len_ind: &[u8], data: &[u8]) {
+        if self.enabled {
+            self.record_value(field, offset, len_ind, data, field.to_string(&data.to_vec()));
+        }
+    }
+
+    /// Records a field that was read at offset along with its decoded value, for fields whose wire
+    /// format differs from the data held in the message
+    pub fn record_value(&mut self, field: &dyn Field, offset: usize, len_ind: &[u8], data: &[u8], value: String) {
+        if self.enabled {
+            self.fields.push(FieldTrace {
+                name: field.name().clone(),
+                position: field.position(),
+                offset,
+                len_ind: len_ind.to_vec(),
+                data: data.to_vec(),
+                value,
+            });
+        }
+    }
+
+    /// Returns the recorded fields
+    pub fn into_fields(self) -> Vec<FieldTrace> {
+        self.fields
+    }
+}
+
+/// This struct represents the result of Spec::parse_traced
+pub struct ParseReport<'a> {
+    /// The parsed message. On failure, it contains the fields parsed until the failure (or is None if
+    /// the message segment could not be determined)
+    pub msg: Option<IsoMsg<'a>>,
+    /// The fields in the order they were parsed
+    pub fields: Vec<FieldTrace>,
+    /// The error that stopped the parse (if any)
+    pub error: Option<ParseError>,
+    data: Vec<u8>,
+}
+
+impl<'a> ParseReport<'a> {
+    pub(in crate::iso8583) fn new(msg: Option<IsoMsg<'a>>, fields: Vec<FieldTrace>, error: Option<ParseError>, data: &[u8]) -> ParseReport<'a> {
+        ParseReport { msg, fields, error, data: data.to_vec() }
+    }
+
+    /// Returns true if the message was parsed successfully
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Returns the number of bytes of the message that were read into fields
+    pub fn consumed(&self) -> usize {
+        self.fields.iter().map(|f| f.offset + f.len_ind.len() + f.data.len()).max().unwrap_or(0)
+    }
+
+    /// Returns a hexdump of the message where each field is annotated with its name, position and value
+    pub fn hexdump(&self) -> String {
+        let mut dump = String::new();
+        dump.push('\n');
+
+        for f in &self.fields {
+            let mut annotation = f.name.clone();
+            if f.position > 0 {
+                annotation += format!(" ({:03})", f.position).as_str();
+            }
+            if !f.len_ind.is_empty() {
+                annotation += format!(" [len-ind: {}]", hex::encode(&f.len_ind)).as_str();
+            }
+            annotation += format!(" = {}", f.value).as_str();
+
+            let mut raw = f.len_ind.clone();
+            raw.extend_from_slice(&f.data);
+            dump_lines(&mut dump, &raw, f.offset, annotation.as_str());
+        }
+
+        let consumed = self.consumed();
+        if consumed < self.data.len() {
+            let annotation = if self.error.is_some() { "<not parsed>" } else { "<trailing data>" };
+            dump_lines(&mut dump, &self.data[consumed..], consumed, annotation);
+        }
+
+        if let Some(e) = &self.error {
+            dump.push_str(format!("error: {}\n", e).as_str());
+        }
+        dump
+    }
+}
+
+impl Display for ParseReport<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.hexdump().as_str())
+    }
+}
+
+/// Appends data (at offset in the message) as hexdump lines, the first of which is annotated
+fn dump_lines(dump: &mut String, data: &[u8], offset: usize, annotation: &str) {
+    if data.is_empty() {
+        dump.push_str(format!("{:37} {:16} {:08x}  {}\n", "||", "", offset, annotation).as_str());
+        return;
+    }
+
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let hex_str = chunk.chunks(4).map(hex::encode).collect::<Vec<String>>().join(" ");
+        let ascii = chunk.iter().map(|b| sanitize_byte(*b)).collect::<String>();
+        let note = if i == 0 { annotation } else { "" };
+        dump.push_str(format!("{:37} {:16} {:08x}  {}", format!("|{}|", hex_str), ascii, offset + i * 16, note).trim_end());
+        dump.push('\n');
+    }
+}
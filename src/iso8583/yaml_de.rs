@@ -1,23 +1,55 @@
-//! This module contains implementation of spec deserialization logic from a YAML file
+//! This module contains implementation of spec deserialization logic from a YAML (or JSON or TOML) file. A spec
+//! can extend a base spec (extends) and its fields can refer (ref) to a top level field dictionary (fields),
+//! these are resolved before the definition is deserialized
+use std::collections::HashMap;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 use crate::iso8583::bitmap::BmpField;
-use crate::iso8583::field::{Encoding, Field, FixedField, VarField};
-use crate::iso8583::iso_spec::{MessageSegment, Spec};
-use crate::iso8583::IsoError;
+use crate::iso8583::field::{BcdPadding, ContentRules, ContentType, Encoding, Field, FixedField, Justify, LenUnit, Overflow, Padding, VarField};
+use crate::iso8583::iso_spec::{MessageSegment, Spec, SpecError, SpecFormat};
+use crate::iso8583::spec_validator::validate_spec;
+use crate::iso8583::tlv::{TlvField, TlvFormat};
+use crate::iso8583::rules::{FieldRule, Presence};
+use crate::iso8583::defaults::{FieldDefault, Generator, StanCounter};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct YField {
     pub name: String,
     pub id: u32,
     pub len: u32,
-    #[serde(alias = "type")]
+    #[serde(rename = "type", alias = "field_type")]
     pub field_type: String,
     pub len_encoding: Option<Encoding>,
     pub data_encoding: Encoding,
     pub position: Option<u32>,
     pub children: Option<Vec<YField>>,
+    pub tlv_format: Option<YTlvFormat>,
+    pub bcd_padding: Option<BcdPadding>,
+    pub len_unit: Option<LenUnit>,
+    pub pad_char: Option<char>,
+    pub justify: Option<Justify>,
+    pub overflow: Option<Overflow>,
+    pub content: Option<ContentType>,
+    pub min_len: Option<u32>,
+    pub max_len: Option<u32>,
+    pub lenient: Option<bool>,
+    pub presence: Option<Presence>,
+    pub when: Option<String>,
+    pub default: Option<String>,
+    pub generator: Option<Generator>,
+    pub echo: Option<bool>,
+}
+
+/// The format of the tags and lengths of the data elements of an AsciiTlv field
+#[derive(Serialize, Deserialize, Clone)]
+pub struct YTlvFormat {
+    pub tag_len: u32,
+    pub tag_encoding: Encoding,
+    pub len_len: u32,
+    pub len_encoding: Encoding,
 }
 
 impl Into<Box<dyn Field>> for &YField {
@@ -30,9 +62,14 @@ impl Into<Box<dyn Field>> for &YField {
                     len: self.len,
                     encoding: self.data_encoding.clone(),
                     position: self.position.unwrap_or(0),
+                    children: children_of(self),
+                    bcd_padding: self.bcd_padding,
+                    padding: Padding { pad_char: self.pad_char, justify: self.justify, overflow: self.overflow },
+                    rules: rules_of(self),
                 })
             }
             "Variable" => {
+                // len_encoding is verified to be present by validate_spec
                 Box::new(VarField {
                     name: self.name.clone(),
                     id: self.id,
@@ -40,22 +77,45 @@ impl Into<Box<dyn Field>> for &YField {
                     len_encoding: self.len_encoding.unwrap(),
                     encoding: self.data_encoding.clone(),
                     position: self.position.unwrap_or(0),
+                    children: children_of(self),
+                    bcd_padding: self.bcd_padding,
+                    len_unit: self.len_unit,
+                    rules: rules_of(self),
+                })
+            }
+            "Tlv" | "AsciiTlv" => {
+                // len_encoding (and tlv_format for AsciiTlv) is verified to be present by validate_spec
+                let format = match &self.tlv_format {
+                    Some(f) if self.field_type == "AsciiTlv" => TlvFormat::Ascii {
+                        tag_len: f.tag_len,
+                        tag_encoding: f.tag_encoding,
+                        len_len: f.len_len,
+                        len_encoding: f.len_encoding,
+                    },
+                    _ => TlvFormat::Ber
+                };
+                Box::new(TlvField {
+                    format,
+                    var: VarField {
+                        name: self.name.clone(),
+                        id: self.id,
+                        len: self.len,
+                        len_encoding: self.len_encoding.unwrap(),
+                        encoding: self.data_encoding,
+                        position: self.position.unwrap_or(0),
+                        children: vec![],
+                        bcd_padding: None,
+                        len_unit: None,
+                        rules: ContentRules::default(),
+                    }
                 })
             }
             "Bitmapped" => {
-                let mut children: Vec<Box<dyn Field>> = Vec::<Box<dyn Field>>::new();
-                if self.children.is_some() {
-                    let ychildren = &self.children.as_ref().unwrap();
-                    &ychildren.iter().for_each(|f| {
-                        children.push(Into::<Box<dyn Field>>::into(f));
-                    });
-                }
-
                 Box::new(BmpField {
                     name: self.name.clone(),
                     id: self.id,
                     encoding: self.data_encoding.clone(),
-                    children,
+                    children: children_of(self),
                 })
             }
             _ => {
@@ -65,6 +125,62 @@ impl Into<Box<dyn Field>> for &YField {
     }
 }
 
+pub(in crate::iso8583) fn rules_of(y_field: &YField) -> ContentRules {
+    ContentRules {
+        content_type: y_field.content,
+        min_len: y_field.min_len,
+        max_len: y_field.max_len,
+        lenient: y_field.lenient.unwrap_or(false),
+    }
+}
+
+/// Returns the presence rules of fields (and their children)
+pub(in crate::iso8583) fn presence_rules_of(fields: &[YField]) -> Vec<FieldRule> {
+    let mut rules = vec![];
+    for f in fields {
+        if let Some(presence) = f.presence {
+            rules.push(FieldRule { field: f.name.clone(), presence, when: f.when.clone() });
+        }
+        if let Some(children) = &f.children {
+            rules.extend(presence_rules_of(children));
+        }
+    }
+    rules
+}
+
+/// Returns the default values, generators and echo flags of fields (and their children). Fields with
+/// a echo presence are echoed as well
+pub(in crate::iso8583) fn defaults_of(fields: &[YField]) -> Vec<FieldDefault> {
+    let mut defaults = vec![];
+    for f in fields {
+        let echo = f.echo.unwrap_or(false) || f.presence == Some(Presence::Echo);
+        if f.default.is_some() || f.generator.is_some() || echo {
+            defaults.push(FieldDefault { field: f.name.clone(), default: f.default.clone(), generator: f.generator, echo });
+        }
+        if let Some(children) = &f.children {
+            defaults.extend(defaults_of(children));
+        }
+    }
+    defaults
+}
+
+/// Sets lenient on all fields (and their children) that do not set it themselves
+fn apply_lenient(fields: &mut [YField], lenient: bool) {
+    fields.iter_mut().for_each(|f| {
+        f.lenient.get_or_insert(lenient);
+        if let Some(children) = &mut f.children {
+            apply_lenient(children, lenient);
+        }
+    });
+}
+
+fn children_of(y_field: &YField) -> Vec<Box<dyn Field>> {
+    match &y_field.children {
+        Some(children) => children.iter().map(|f| f.into()).collect(),
+        None => vec![]
+    }
+}
+
 
 // Spec is the definition of the spec - layout of fields etc..
 #[derive(Serialize, Deserialize)]
@@ -73,6 +189,8 @@ pub struct YSpec {
     pub(crate) id: u32,
     pub(crate) messages: Vec<YMessageSegment>,
     pub(crate) header_fields: Vec<YField>,
+    /// When true, content rule violations are only logged (unless a field sets lenient itself)
+    pub(crate) lenient: Option<bool>,
 }
 
 
@@ -97,6 +215,26 @@ impl Into<Spec> for YSpec {
             id: self.id,
             messages,
             header_fields,
+            stan: StanCounter::default(),
+            definition: self,
+        }
+    }
+}
+
+
+impl YSpec {
+    /// Validates the definition and converts it into a Spec, src is the YAML source (if any)
+    /// the definition was read from
+    pub(in crate::iso8583) fn into_spec(mut self, src: Option<&str>) -> Result<Spec, SpecError> {
+        let diagnostics = validate_spec(&self, src);
+        if diagnostics.is_empty() {
+            if let Some(lenient) = self.lenient {
+                apply_lenient(&mut self.header_fields, lenient);
+                self.messages.iter_mut().for_each(|m| apply_lenient(&mut m.fields, lenient));
+            }
+            Ok(self.into())
+        } else {
+            Err(SpecError { diagnostics })
         }
     }
 }
@@ -111,31 +249,285 @@ pub struct YMessageSegment {
 }
 
 
-pub fn read_spec(spec_file: &str) -> Result<Spec, IsoError> {
-    match std::fs::File::open(spec_file) {
-        Ok(f) => {
-            let mut yaml_str = String::new();
-            let _ = (&f).read_to_string(&mut yaml_str);
+/// The key of a field that refers to a field of the field dictionary
+const REF_KEY: &str = "ref";
+
+/// Reads a spec from a YAML, JSON or TOML file (see SpecFormat::from_path, the content of the file is used
+/// when the extension is not known), a base spec (see extends) is read relative to the directory of the file.
+/// Problems that are not about a element of the spec (like a syntax error) have spec_file as their path
+pub fn read_spec(spec_file: &str) -> Result<Spec, SpecError> {
+    let path = Path::new(spec_file);
+    match std::fs::read_to_string(path) {
+        Ok(src) => {
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            let format = SpecFormat::from_path(path).unwrap_or_else(|| SpecFormat::detect(&src));
+            read_spec_in(&src, format, dir, vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())])
+                .map_err(|mut e| {
+                    e.diagnostics.iter_mut().filter(|d| d.path.is_empty()).for_each(|d| d.path = spec_file.to_string());
+                    e
+                })
+        }
+        Err(e) => {
+            Err(SpecError::new(spec_file, e.to_string().as_str(), None))
+        }
+    }
+}
+
+/// Reads a spec from a YAML string, the spec is validated and all problems found are returned
+/// as a SpecError. A base spec (see extends) is read relative to the current directory of the process
+/// (not of the caller's source file), use read_spec for a spec that extends another
+pub fn read_spec_from_str(yaml_str: &str) -> Result<Spec, SpecError> {
+    read_spec_in(yaml_str, SpecFormat::Yaml, Path::new("."), vec![])
+}
+
+/// Reads a spec from a string in the given format, a base spec (see extends) is read relative to the
+/// current directory of the process
+pub fn read_spec_from_str_as(src: &str, format: SpecFormat) -> Result<Spec, SpecError> {
+    read_spec_in(src, format, Path::new("."), vec![])
+}
+
+/// Reads a spec from a string in format whose base spec (if any) is relative to dir, seen holds the spec files
+/// already read (to detect a cycle of base specs)
+fn read_spec_in(src: &str, format: SpecFormat, dir: &Path, mut seen: Vec<PathBuf>) -> Result<Spec, SpecError> {
+    let value = to_value(src, format)?;
+
+    // without a base spec or a field dictionary a YAML definition is read as is, so that problems can be
+    // reported with their line in the source
+    if format == SpecFormat::Yaml && value.get("extends").is_none() && value.get("fields").is_none() {
+        return match serde_yaml::from_str::<YSpec>(src) {
+            Ok(y_spec) => y_spec.into_spec(Some(src)),
+            Err(e) => Err(yaml_error(e))
+        };
+    }
 
-            match serde_yaml::from_str::<YSpec>(&yaml_str) {
-                Ok(y_spec) => {
-                    Ok(y_spec.into())
+    // problems are still reported with their line in a YAML source, a field that refers to the field dictionary
+    // with the line of its ref and a element of the base spec without a line. The lines of elements are only
+    // looked up in YAML, problems in a JSON or TOML definition have no line (except for syntax errors)
+    let mut value = merge_base(value, dir, &mut seen)?;
+    resolve_refs(&mut value)?;
+    match serde_yaml::from_value::<YSpec>(value) {
+        Ok(y_spec) => y_spec.into_spec((format == SpecFormat::Yaml).then_some(src)),
+        Err(e) => Err(SpecError::new("", e.to_string().as_str(), None))
+    }
+}
+
+fn yaml_error(e: serde_yaml::Error) -> SpecError {
+    SpecError::new("", e.to_string().as_str(), e.location().map(|l| l.line()))
+}
+
+/// Returns the definition in src (in format) as a YAML value, JSON and TOML share the schema of YAML
+fn to_value(src: &str, format: SpecFormat) -> Result<Value, SpecError> {
+    match format {
+        SpecFormat::Yaml => serde_yaml::from_str::<Value>(src).map_err(yaml_error),
+        SpecFormat::Json => serde_json::from_str::<Value>(src)
+            .map_err(|e| SpecError::new("", e.to_string().as_str(), Some(e.line()))),
+        SpecFormat::Toml => toml::from_str::<Value>(src)
+            .map_err(|e| SpecError::new("", e.to_string().as_str(), e.line_col().map(|(line, _)| line + 1))),
+    }
+}
+
+/// Returns the definition y_spec in format
+pub(in crate::iso8583) fn write_spec(y_spec: &YSpec, format: SpecFormat) -> Result<String, SpecError> {
+    let mut value = serde_json::to_value(y_spec).map_err(|e| SpecError::new("", e.to_string().as_str(), None))?;
+    // unset attributes are left out (TOML has no null)
+    strip_nulls(&mut value);
+    match format {
+        SpecFormat::Yaml => serde_yaml::to_string(&value).map_err(yaml_error),
+        SpecFormat::Json => serde_json::to_string_pretty(&value).map_err(|e| SpecError::new("", e.to_string().as_str(), None)),
+        SpecFormat::Toml => toml::Value::try_from(value).and_then(|v| toml::to_string(&v))
+            .map_err(|e| SpecError::new("", e.to_string().as_str(), None)),
+    }
+}
+
+/// Removes the null attributes of the objects in value
+fn strip_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+/// Returns the definition in value merged into its base spec (if it extends one). The top level attributes
+/// of value replace those of the base, except for the field dictionary and messages that are merged by name
+fn merge_base(value: Value, dir: &Path, seen: &mut Vec<PathBuf>) -> Result<Value, SpecError> {
+    let base_file = match value.get("extends") {
+        None => return Ok(value),
+        Some(Value::String(f)) => dir.join(f),
+        Some(_) => return Err(SpecError::new("extends", "extends should be the path of a spec file", None))
+    };
+    let base_path = base_file.to_string_lossy().to_string();
+
+    let canonical = base_file.canonicalize().unwrap_or_else(|_| base_file.clone());
+    if seen.contains(&canonical) {
+        return Err(SpecError::new("extends", format!("{} is extended in a cycle", base_path).as_str(), None));
+    }
+    seen.push(canonical);
+
+    let base_str = std::fs::read_to_string(&base_file).map_err(|e| SpecError::new(&base_path, e.to_string().as_str(), None))?;
+    let format = SpecFormat::from_path(&base_file).unwrap_or_else(|| SpecFormat::detect(&base_str));
+    let base = to_value(&base_str, format).map_err(|mut e| {
+        e.diagnostics.iter_mut().for_each(|d| d.path = base_path.clone());
+        e
+    })?;
+    let mut merged = merge_base(base, base_file.parent().unwrap_or(dir), seen)?;
+
+    if let (Value::Mapping(merged), Value::Mapping(value)) = (&mut merged, value) {
+        merged.remove(&Value::from("extends"));
+        for (k, v) in value {
+            match (k.as_str(), merged.get_mut(&k)) {
+                (Some("extends"), _) => {}
+                (Some("fields"), Some(Value::Sequence(base))) | (Some("messages"), Some(Value::Sequence(base))) => {
+                    if let Value::Sequence(v) = v {
+                        merge_by_name(base, v);
+                    }
+                }
+                _ => {
+                    merged.insert(k, v);
                 }
-                Err(e) => Err(IsoError { msg: e.to_string() })
             }
         }
-        Err(e) => {
-            Err(IsoError { msg: e.to_string() })
+    }
+    Ok(merged)
+}
+
+/// Merges values into base, a value replaces the element of base with the same name or is appended to it
+fn merge_by_name(base: &mut Vec<Value>, values: Vec<Value>) {
+    for v in values {
+        match base.iter_mut().find(|b| b.get("name").is_some() && b.get("name") == v.get("name")) {
+            Some(b) => *b = v,
+            None => base.push(v)
+        }
+    }
+}
+
+/// Replaces the fields that refer to the field dictionary (with a ref) by their definition in the dictionary,
+/// the other attributes of the referring field override those of the definition
+fn resolve_refs(value: &mut Value) -> Result<(), SpecError> {
+    let dictionary: HashMap<String, Value> = match value.get("fields") {
+        Some(Value::Sequence(fields)) => fields.iter()
+            .filter_map(|f| f.get("name").and_then(|n| n.as_str()).map(|n| (n.to_string(), f.clone())))
+            .collect(),
+        _ => HashMap::new()
+    };
+
+    if let Some(Value::Sequence(fields)) = value.get_mut("header_fields") {
+        resolve_fields(fields, &dictionary, "header_fields", &mut vec![])?;
+    }
+    if let Some(Value::Sequence(messages)) = value.get_mut("messages") {
+        for m in messages {
+            let path = format!("messages[{}]", m.get("name").and_then(|n| n.as_str()).unwrap_or_default());
+            if let Some(Value::Sequence(fields)) = m.get_mut("fields") {
+                resolve_fields(fields, &dictionary, &path, &mut vec![])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the refs of fields (and their children), refs holds the refs being resolved (to detect a cycle)
+fn resolve_fields(fields: &mut [Value], dictionary: &HashMap<String, Value>, path: &str, refs: &mut Vec<String>) -> Result<(), SpecError> {
+    for f in fields {
+        let depth = refs.len();
+        while let Some(name) = f.get(REF_KEY).and_then(|r| r.as_str()).map(|r| r.to_string()) {
+            let field_path = format!("{}.{}", path, name);
+            if refs.contains(&name) {
+                return Err(SpecError::new(&field_path, format!("field {} is referred to in a cycle", name).as_str(), None));
+            }
+            let mut resolved = match dictionary.get(&name) {
+                Some(def) => def.clone(),
+                None => return Err(SpecError::new(&field_path, format!("no field {} in the field dictionary", name).as_str(), None))
+            };
+            if let (Value::Mapping(resolved), Value::Mapping(overrides)) = (&mut resolved, &*f) {
+                overrides.iter().filter(|(k, _)| k.as_str() != Some(REF_KEY)).for_each(|(k, v)| {
+                    resolved.insert(k.clone(), v.clone());
+                });
+            }
+            refs.push(name);
+            *f = resolved;
+        }
+
+        let field_path = format!("{}.{}", path, f.get("name").and_then(|n| n.as_str()).unwrap_or_default());
+        if let Some(Value::Sequence(children)) = f.get_mut("children") {
+            resolve_fields(children, dictionary, &field_path, refs)?;
         }
+        refs.truncate(depth);
+    }
+    Ok(())
+}
+
+/// Reads a spec from YAML held in a byte slice (for example, bytes embedded via include_bytes!)
+pub fn read_spec_from_slice(yaml_data: &[u8]) -> Result<Spec, SpecError> {
+    match std::str::from_utf8(yaml_data) {
+        Ok(yaml_str) => read_spec_from_str(yaml_str),
+        Err(e) => Err(SpecError::new("", format!("spec is not valid utf-8: {}", e).as_str(), None))
+    }
+}
+
+/// Reads a spec from a reader that supplies YAML
+pub fn read_spec_from_reader<R: Read>(mut reader: R) -> Result<Spec, SpecError> {
+    let mut yaml_str = String::new();
+    match reader.read_to_string(&mut yaml_str) {
+        Ok(_) => read_spec_from_str(&yaml_str),
+        Err(e) => Err(SpecError::new("", e.to_string().as_str(), None))
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::iso8583::yaml_de::read_spec;
+    use crate::iso8583::iso_spec::new_msg;
+    use crate::iso8583::yaml_de::{read_spec, read_spec_from_str};
     use std::path::Path;
 
+    const BASE_SPEC: &str = r#"
+name: Base
+id: 1
+header_fields:
+  - name: "message_type"
+    id: 1
+    type: Fixed
+    len: 4
+    data_encoding: ASCII
+fields:
+  - name: "message_type"
+    id: 1
+    type: Fixed
+    len: 4
+    data_encoding: ASCII
+  - name: "pan"
+    id: 2
+    type: Variable
+    len: 2
+    len_encoding: ASCII
+    data_encoding: ASCII
+    position: 2
+  - name: "additional_data"
+    id: 48
+    type: Fixed
+    len: 10
+    data_encoding: ASCII
+    position: 48
+messages:
+  - name: "Auth"
+    id: 1
+    selector: ["1100"]
+    fields:
+      - ref: "message_type"
+      - name: "bitmap"
+        id: 2
+        type: Bitmapped
+        len: 0
+        data_encoding: BINARY
+        children:
+          - ref: "pan"
+          - ref: "additional_data"
+            name: "private_data"
+"#;
+
     #[test]
     fn test_deserialize_yaml_spec() {
         let path = Path::new(".").join("sample_spec").join("sample_spec.yaml");
@@ -145,7 +537,69 @@ mod tests {
             Ok(spec) => {
                 assert_eq!(2, (&spec.messages).len());
             }
-            Err(e) => assert!(false, e)
+            Err(e) => assert!(false, "{}", e)
         };
     }
+
+    #[test]
+    fn test_extends_base_spec() {
+        let dir = std::env::temp_dir().join(format!("iso8583_extends_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("base.yaml"), BASE_SPEC).unwrap();
+        std::fs::write(dir.join("variant.yaml"), r#"
+extends: "base.yaml"
+name: Variant
+id: 2
+fields:
+  - name: "additional_data"
+    id: 48
+    type: Variable
+    len: 3
+    len_encoding: ASCII
+    data_encoding: ASCII
+    position: 48
+"#).unwrap();
+        std::fs::write(dir.join("cycle_1.yaml"), "extends: cycle_2.yaml\nname: Cycle\nid: 3\n").unwrap();
+        std::fs::write(dir.join("cycle_2.yaml"), "extends: cycle_1.yaml\n").unwrap();
+        std::fs::write(dir.join("broken.json"), "{\n  \"name\": ").unwrap();
+
+        let base = read_spec(dir.join("base.yaml").to_str().unwrap()).unwrap();
+        let variant = read_spec(dir.join("variant.yaml").to_str().unwrap()).unwrap();
+        let cycle = read_spec(dir.join("cycle_1.yaml").to_str().unwrap());
+        let broken_file = dir.join("broken.json").to_str().unwrap().to_string();
+        let broken = read_spec(&broken_file);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the renamed additional_data is fixed in the base spec and variable in the variant
+        assert_eq!((base.name(), variant.name(), variant.id()), ("Base", "Variant", 2));
+        for (spec, expected) in [(&base, "XYZ       "), (&variant, "003XYZ")] {
+            let mut iso_msg = new_msg(spec, spec.get_message_from_header("1100").unwrap());
+            iso_msg.set("message_type", "1100").unwrap();
+            iso_msg.set("private_data", "XYZ").unwrap();
+            // after the message_type and the primary bitmap
+            assert_eq!(&iso_msg.assemble().unwrap()[12..], expected.as_bytes());
+        }
+
+        assert!(cycle.err().unwrap().diagnostics[0].message.ends_with("is extended in a cycle"));
+        // a syntax error has the spec file as its path
+        let broken = broken.err().unwrap();
+        assert_eq!(broken.diagnostics[0].path, broken_file);
+        assert!(broken.diagnostics[0].line.is_some());
+    }
+
+    #[test]
+    fn test_field_dictionary_errors() {
+        let err = read_spec_from_str(&BASE_SPEC.replace("- ref: \"pan\"", "- ref: \"no_such_field\"")).err().unwrap();
+        assert_eq!(err.diagnostics[0].path, "messages[Auth].bitmap.no_such_field");
+        assert_eq!(err.diagnostics[0].message, "no field no_such_field in the field dictionary");
+
+        let err = read_spec_from_str(&BASE_SPEC.replace("    position: 48\n", "    position: 48\n    children:\n      - ref: \"additional_data\"\n")).err().unwrap();
+        assert_eq!(err.diagnostics[0].message, "field additional_data is referred to in a cycle");
+
+        // problems of a field that refers to the dictionary are reported with the line of the ref
+        let err = read_spec_from_str(&BASE_SPEC.replace("- ref: \"pan\"\n", "- ref: \"pan\"\n            position: 200\n")
+            .replace("name: \"private_data\"\n", "name: \"private_data\"\n            position: 1\n")).err().unwrap();
+        let found: Vec<(&str, Option<usize>)> = err.diagnostics.iter().map(|d| (d.path.as_str(), d.line)).collect();
+        assert_eq!(found, vec![("messages[Auth].bitmap.pan", Some(41)), ("messages[Auth].bitmap.private_data", Some(44))]);
+    }
 }